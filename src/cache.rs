@@ -0,0 +1,185 @@
+use config::HashSettings;
+use img::Image;
+
+use img_hash::ImageHash;
+
+use std::collections::HashMap;
+use std::io::{BufferedReader, IoResult, IoErrorKind, FileStat};
+use std::io::fs::{mod, File, PathExtensions};
+
+const HEADER: &'static str = "# img_dup hash cache, version 1";
+
+/// Hashes from previous runs, so unchanged files don't have to be decoded again.
+///
+/// Entries are keyed on the path and hash settings, and are only used if the file's
+/// size and modification time haven't changed since it was hashed.
+pub struct HashCache {
+    entries: HashMap<Key, Entry>,
+}
+
+#[deriving(PartialEq, Eq, Hash, Clone)]
+struct Key {
+    path: Path,
+    hash_size: u32,
+    fast: bool,
+}
+
+struct Entry {
+    size: u64,
+    modified: u64,
+    width: u32,
+    height: u32,
+    hash: ImageHash,
+    pixel_digest: Option<String>,
+}
+
+impl HashCache {
+    pub fn new() -> HashCache {
+        HashCache { entries: HashMap::new() }
+    }
+
+    /// Load the cache, which is empty if the file doesn't exist yet.
+    pub fn load(path: &Path) -> IoResult<HashCache> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind == IoErrorKind::FileNotFound => return Ok(HashCache::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut cache = HashCache::new();
+
+        for line in BufferedReader::new(file).lines() {
+            let line = try!(line);
+            let line = line.trim_right_matches('\n');
+
+            if line.is_empty() || line.starts_with("#") { continue; }
+
+            // A bad line costs us one cache miss, not the whole cache
+            if let Some((key, entry)) = parse_line(line) {
+                cache.entries.insert(key, entry);
+            }
+        }
+
+        Ok(cache)
+    }
+
+    /// The cached hash of the file at `path`, if it hasn't changed since.
+    pub fn get(&self, path: &Path, stat: &FileStat, settings: &HashSettings) -> Option<Image> {
+        let key = Key::new(path.clone(), settings);
+
+        self.entries.get(&key)
+            .and_then(|entry| if entry.size == stat.size && entry.modified == stat.modified {
+                let mut image = Image::new(path.clone(), entry.hash.clone(), entry.width, entry.height);
+                image.pixel_digest = entry.pixel_digest.clone();
+                Some(image)
+            } else {
+                None
+            })
+    }
+
+    pub fn insert(&mut self, image: &Image, settings: &HashSettings) {
+        self.entries.insert(Key::new(image.path.clone(), settings), Entry {
+            size: image.size,
+            modified: image.modified,
+            width: image.width,
+            height: image.height,
+            hash: image.hash.clone(),
+            pixel_digest: image.pixel_digest.clone(),
+        });
+    }
+
+    /// Write the cache to a temporary file next to `path` first, so a run that dies halfway
+    /// through saving doesn't leave a truncated cache behind.
+    pub fn save(&self, path: &Path) -> IoResult<()> {
+        let mut tmp_path = path.as_vec().to_vec();
+        tmp_path.push_all(b".tmp");
+        let tmp_path = Path::new(tmp_path);
+
+        let mut file = try!(File::create(&tmp_path));
+
+        try!(file.write_line(HEADER));
+
+        for (key, entry) in self.entries.iter() {
+            // Paths go last since they may contain tabs
+            try!(writeln!(&mut file, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                key.hash_size, key.fast,
+                entry.size, entry.modified, entry.width, entry.height,
+                entry.hash.to_base64(),
+                entry.pixel_digest.as_ref().map_or("-", |digest| &**digest),
+                key.path.display()
+            ));
+        }
+
+        try!(file.fsync());
+        // Close it before it's renamed
+        drop(file);
+
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Drop the entries for files that no longer exist.
+    fn evict_missing(&mut self) {
+        let missing: Vec<Key> = self.entries.keys()
+            .filter(|key| !key.path.exists())
+            .map(|key| key.clone())
+            .collect();
+
+        for key in missing.iter() {
+            self.entries.remove(key);
+        }
+    }
+}
+
+impl Key {
+    fn new(path: Path, settings: &HashSettings) -> Key {
+        Key {
+            path: path,
+            hash_size: settings.hash_size,
+            fast: settings.fast,
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<(Key, Entry)> {
+    let fields: Vec<&str> = line.splitn(8, '\t').collect();
+
+    if fields.len() != 9 { return None; }
+
+    let hash = ImageHash::from_base64(fields[6]).ok();
+    let pixel_digest = if fields[7] == "-" { None } else { Some(fields[7].to_string()) };
+
+    match (fields[0].parse(), fields[1].parse(), fields[2].parse(), fields[3].parse(),
+           fields[4].parse(), fields[5].parse(), hash) {
+        (Some(hash_size), Some(fast), Some(size), Some(modified),
+         Some(width), Some(height), Some(hash)) => Some((
+            Key {
+                path: Path::new(fields[8]),
+                hash_size: hash_size,
+                fast: fast,
+            },
+            Entry {
+                size: size,
+                modified: modified,
+                width: width,
+                height: height,
+                hash: hash,
+                pixel_digest: pixel_digest,
+            }
+        )),
+        _ => None,
+    }
+}
+
+/// Add newly hashed images to the cache file, and drop those of files that were deleted.
+pub fn update(path: &Path, settings: &HashSettings, images: &[Image]) -> IoResult<()> {
+    // Reload in case another run has updated it in the meantime
+    let mut cache = try!(HashCache::load(path));
+
+    for image in images.iter() {
+        cache.insert(image, settings);
+    }
+
+    cache.evict_missing();
+
+    cache.save(path)
+}
@@ -1,9 +1,19 @@
+use img::{Image, GuardFailure};
+use discovery::SymlinkPolicy;
+use keep::KeepPolicy;
+
 use getopts::{OptGroup, optopt, optmulti, optflag, optflagopt, Matches, usage, getopts};
 
 use serialize::json::{ToJson, Json};
 
+use img_hash::ImageHash;
+
+use time::strptime;
+
 use std::borrow::ToOwned;
 
+use std::cmp::max;
+
 use std::collections::BTreeMap;
 
 use std::fmt::{Show, Formatter};
@@ -12,20 +22,63 @@ use std::fmt::Result as FormatResult;
 use std::io::fs::PathExtensions;
 
 use std::os;
+use std::u64;
 
 #[deriving(Clone)]
 pub struct ProgramSettings {
     pub threads: uint,
-    pub dir: Path,
+    /// The directories to search, each labeled as its own root in the output.
+    pub dirs: Vec<Path>,
+    pub reference_dirs: Vec<Path>,
     pub recurse: bool,
+    /// How many levels of subdirectories to search with `recurse`, if limited.
+    pub max_depth: Option<uint>,
+    pub cross_root: bool,
     pub exts: Vec<String>,    
+    pub sniff: bool,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub files_from: Option<PathList>,
+    pub symlinks: SymlinkPolicy,
+    pub null_separated: bool,
+    /// File size limits in bytes.
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Modification time limits, in ms since the epoch.
+    pub newer_than: Option<u64>,
+    pub older_than: Option<u64>,
+    /// Width and height limits, read from the image headers before decoding.
+    pub min_dimensions: Option<(u32, u32)>,
+    pub max_dimensions: Option<(u32, u32)>,
     pub hash_size: u32,
-    pub threshold: f32,
+    pub threshold: Threshold,
+    /// If `threshold` wasn't given and should follow the hash settings.
+    pub default_threshold: bool,
+    pub aspect_tolerance: Option<f32>,
+    pub min_scale: Option<f32>,
+    pub max_scale: Option<f32>,
     pub fast: bool,
+    pub exact: bool,
     pub outfile: Option<Path>,
     pub dup_only: bool,
+    pub keep: Vec<KeepPolicy>,
     pub limit: uint,
     pub json: JsonSettings,
+    pub cache: Option<Path>,
+    pub xattr: bool,
+    pub journal: Option<Path>,
+    pub resume: bool,
+    pub ignore_list: Path,
+    pub list_ignored: bool,
+    pub prune_ignored: bool,
+    pub save_hashes: Option<Path>,
+    pub load_hashes: Option<Path>,
+    pub update_index: Option<Path>,
+    pub export_index: Option<Path>,
+    pub import_index: Vec<Path>,
+    pub index_label: Option<String>,
+    pub query: Option<String>,
+    pub top: uint,
 	pub gui: bool,
 }
 
@@ -39,12 +92,30 @@ impl ProgramSettings {
                    "How many threads the program should use to process images.
                    Defaults to the number of cores reported by the OS.",
                    "[1+]"),
-            optopt("d", "dir",
-                   "The directory the program should search in. 
-                   Default is the current working directory.",
-                   "[directory]"),
+            optmulti("d", "dir",
+                     "The directory the program should search in. 
+                     Default is the current working directory. May be given
+                     multiple times to search several roots, whose paths are
+                     then shown relative to and labeled with their root. Output
+                     and other files default to being in the first.",
+                     "[directory]"),
+            optmulti("", "reference",
+                     "A directory of trusted reference images. If given, images
+                     in the search directory are only matched against these,
+                     never against each other, and reference images are never
+                     matched against each other. May be given multiple times.",
+                     "[directory]"),
             optflag("r", "recurse",
                     "If present, the program will search subdirectories."),
+            optopt("", "max-depth",
+                   "With --recurse, search at most this many levels of
+                   subdirectories below the search directory.",
+                   "[0+]"),
+            optflag("x", "cross-root",
+                    "Only match images that are in different search roots.
+                    The roots are each --dir if several are given, otherwise
+                    each immediate subdirectory of the search directory, plus
+                    each reference directory. Results are grouped by root."),
             optopt("h", "hash-size",
                    "Helps the program decide the number of bits to use for the hash.
                    A higher number means more detail, but greater memory usage.
@@ -52,16 +123,98 @@ impl ProgramSettings {
                    "[1+]"),
             optopt("s", "threshold",
                    "The amount in percentage that an image must be different from
-                   another to qualify as unique. Default is 3",
+                   another to qualify as unique. The default depends on the
+                   hash size and algorithm; see --threshold-bits.",
                    "[0.01 - 99.99]"),
+            optopt("", "threshold-bits",
+                   "The maximum number of bits two hashes may differ by for the
+                   images to be considered similar. Cannot be combined with
+                   --threshold. Defaults to roughly 3% of the hash bits for the
+                   DCT hash and 2% for the fast hash, and at least 1.",
+                   "[0+]"),
+            optopt("", "aspect-tolerance",
+                   "Reject matches whose aspect ratios differ by more than
+                   this percentage, even if their hashes match.",
+                   "[0+]"),
+            optopt("", "min-scale",
+                   "Reject matches less than this fraction of the size
+                   (width and height) of the image they matched.",
+                   "[ratio]"),
+            optopt("", "max-scale",
+                   "Reject matches more than this multiple of the size
+                   (width and height) of the image they matched.",
+                   "[ratio]"),
             optflag("f", "fast",
                     "Use a faster, less accurate algorithm.
                     Really only useful for finding duplicates.
                     Using a low threshold and/or a larger hash is recommended."),
+            optflag("b", "exact",
+                    "Find byte-identical files before decoding. Only one file of
+                    each set of exact copies is decoded and hashed, and copies
+                    are reported as exact rather than perceptual matches."),
             optmulti("e", "ext",
                      "Search for filenames with the given extension.
                      Defaults are jpeg, jpg, png, and gif.",
                      "[extension]"), 
+            optflag("", "sniff",
+                    "Identify images by their content instead of their extension,
+                    so mislabeled and extension-less images are found. Only
+                    formats usually given one of the --ext extensions are
+                    searched for. Files whose extension doesn't match their
+                    content are reported as warnings."),
+            optmulti("", "include",
+                     "Only search for files matching this glob pattern. Patterns
+                     containing a / are matched against the path relative to the
+                     search directory, others against the file name.
+                     May be given multiple times.",
+                     "[pattern]"),
+            optmulti("", "exclude",
+                     "Skip files and directories matching this glob pattern, which
+                     is matched like --include. Excluded directories are not
+                     searched at all. May be given multiple times.",
+                     "[pattern]"),
+            optopt("", "symlinks",
+                   "What to do with symbolic links found while searching: skip
+                   them, follow them (the default), or report links to images
+                   as warnings without processing them. Directories are never
+                   searched twice, so links can't cause loops. Links made by
+                   the GUI's Symlink action are always skipped.",
+                   "[skip|follow|report]"),
+            optopt("", "files-from",
+                   "Process the files listed in the given file, one per line,
+                   instead of searching for images. Use - to read the list from
+                   standard input. Files that can't be read are reported as
+                   errors. If not absolute, the list and the paths in it will
+                   be relative to the search directory. Every file listed is
+                   processed: --ext, --include, --exclude, the size, date and
+                   dimension limits and .imgdupignore files don't apply.",
+                   "[file or -]"),
+            optflag("0", "null",
+                    "Paths in the --files-from list are separated by NUL bytes
+                    instead of newlines, as output by find -print0."),
+            optopt("", "min-size",
+                   "Skip files smaller than this many bytes. May be followed by
+                   K, M or G for kibibytes, mebibytes or gibibytes.",
+                   "[size]"),
+            optopt("", "max-size",
+                   "Skip files larger than this many bytes. May be followed by
+                   K, M or G like --min-size.",
+                   "[size]"),
+            optopt("", "newer-than",
+                   "Skip files last modified before this date (midnight UTC).",
+                   "[YYYY-MM-DD]"),
+            optopt("", "older-than",
+                   "Skip files last modified on or after this date (midnight UTC).",
+                   "[YYYY-MM-DD]"),
+            optopt("", "min-dimensions",
+                   "Skip images narrower or shorter than this, e.g. 64x64 to
+                   leave out icons. Read from the image header, so skipped
+                   images are never decoded.",
+                   "[width]x[height]"),
+            optopt("", "max-dimensions",
+                   "Skip images wider or taller than this. Read from the image
+                   header like --min-dimensions.",
+                   "[width]x[height]"),
             optopt("o", "outfile",
                    "Output to the given file. If omitted, will print to stdout.
                    If not absolute, it will be relative to the search directory.",
@@ -70,6 +223,14 @@ impl ProgramSettings {
                    "Display this help."),
             optflag("u", "dup-only",
                     "Only output images with similars or duplicates."),
+            optmulti("", "keep",
+                     "How to choose the original image of each group. One of
+                     resolution (most pixels), largest (largest file), oldest,
+                     newest, lossless (e.g. PNG over JPEG), or prefer:[directory]
+                     (anything under the directory). May be given multiple times;
+                     later policies break ties in earlier ones. By default, the
+                     first image in path order is the original.",
+                     "[policy]"),
             optopt("l", "limit",
                    "Only process the given number of images.",
                    "[1+]"),
@@ -81,6 +242,88 @@ impl ProgramSettings {
                        Otherwise, the JSON will be in compact format.
                        See the README for details.",
                        "[1+] (optional)"),
+            optflagopt("c", "cache",
+                       "Keep the hashes of images in a cache file, and skip decoding
+                       images that haven't changed since they were cached.
+                       Entries are keyed on path, file size, modification time,
+                       hash size and algorithm. Defaults to .img_dup_cache in the
+                       search directory. If not absolute, it will be relative
+                       to the search directory.",
+                       "[file] (optional)"),
+            optflag("", "xattr",
+                    "Store each image's hash in a user.img_dup.hash extended
+                    attribute on the file, and skip decoding images whose
+                    attribute is still valid. Unlike --cache, this survives
+                    moving and renaming files within a filesystem. Linux only."),
+            optflagopt("", "journal",
+                       "Checkpoint finished hashes and errors to a journal file while
+                       processing, so the scan can be continued with --resume if it
+                       is interrupted. The journal is removed when the scan
+                       finishes. Defaults to .img_dup_journal in the search
+                       directory. If not absolute, it will be relative to the
+                       search directory.",
+                       "[file] (optional)"),
+            optflag("", "resume",
+                    "Continue an interrupted scan from its journal, skipping images
+                    that were already processed. Implies --journal."),
+            optopt("", "ignore-list",
+                   "A list of image pairs that are not duplicates, which are
+                   never matched. Pairs are added from the GUI. Defaults to
+                   img_dup_ignored.txt in the search directory.
+                   If not absolute, it will be relative to the search directory.",
+                   "[file]"),
+            optflag("", "list-ignored",
+                    "Print the ignore list and exit. Pairs whose files no longer
+                    exist are marked as missing."),
+            optflag("", "prune-ignored",
+                    "Remove pairs whose files no longer exist from the ignore
+                    list and exit."),
+            optopt("", "save-hashes",
+                   "Save the hashes of all images processed to the given file,
+                   so they can be grouped again with --load-hashes.
+                   If not absolute, it will be relative to the search directory.",
+                   "[file]"),
+            optopt("", "load-hashes",
+                   "Group the hashes saved with --save-hashes instead of searching
+                   for and hashing images. The hash size and algorithm are
+                   taken from the file. If not absolute, it will be relative
+                   to the search directory.",
+                   "[file]"),
+            optopt("", "update-index",
+                   "Only hash images that are new or modified since the given
+                   hash file was last updated, match them against all images in
+                   it, and report only groups containing a new or modified image.
+                   The file is then updated, and images that no longer exist are
+                   removed from it. It is created if it doesn't exist.
+                   If not absolute, it will be relative to the search directory.",
+                   "[file]"),
+            optopt("", "export-index",
+                   "Save the hashes of all images processed, or loaded with
+                   --load-hashes or --import-index, as a portable index whose
+                   paths are relative to the search directories. See INDEX.md.
+                   If not absolute, it will be relative to the search directory.",
+                   "[file]"),
+            optmulti("", "import-index",
+                     "Group or query the images in a portable index made with
+                     --export-index instead of searching for and hashing images.
+                     If given multiple times, the indexes are merged, and can be
+                     saved as one with --export-index. If not absolute, it will
+                     be relative to the search directory.",
+                     "[file]"),
+            optopt("", "index-label",
+                   "Prefix the search directories with this label in an exported
+                   index, e.g. the name of the machine, so indexes from different
+                   machines can be told apart once merged.",
+                   "[label]"),
+            optopt("q", "query",
+                   "Instead of grouping the images found, list the ones closest
+                   to the given image. Accepts either the path to an image,
+                   or a hash from previous output made with the same
+                   hash size and algorithm.",
+                   "[file or hash]"),
+            optopt("k", "top",
+                   "The number of images to list with --query. Default is 10.",
+                   "[1+]"),
 			optflag("g", "gui",
 				"Open the GUI. Given command-line flags will be set
 				in the configuration dialog."),
@@ -94,20 +337,212 @@ impl ProgramSettings {
         }          
     }
 
+    pub fn guards(&self) -> DimensionGuards {
+        DimensionGuards {
+            aspect_tolerance: self.aspect_tolerance,
+            min_scale: self.min_scale,
+            max_scale: self.max_scale,
+        }
+    }
+
+    pub fn keep_names(&self) -> Vec<String> {
+        self.keep.iter().map(|policy| policy.name()).collect()
+    }
+
+    /// Use the hash settings of previously saved hashes.
+    pub fn use_hash_settings(&mut self, hash_settings: HashSettings) {
+        self.hash_size = hash_settings.hash_size;
+        self.fast = hash_settings.fast;
+
+        if self.default_threshold {
+            self.threshold = Threshold::default_for(self.hash_size, self.fast);
+        }
+    }
+
     pub fn silent_stdout(&self) -> bool {
         self.outfile.is_none() && self.json.is_json()
     }
+
+    /// The first search directory, which output and other files default to being in.
+    pub fn dir(&self) -> &Path {
+        &self.dirs[0]
+    }
+
+    /// The record of symbolic links created by the GUI, so they aren't reported as duplicates.
+    pub fn links_file(&self) -> Path {
+        self.dir().join(LINKS_FILE)
+    }
+
+    /// All directories that should be searched for images.
+    pub fn search_dirs(&self) -> Vec<&Path> {
+        let mut dirs: Vec<&Path> = self.dirs.iter().collect();
+        dirs.extend(self.reference_dirs.iter());
+        dirs
+    }
+
+    /// The search directories, for showing paths in output.
+    pub fn roots(&self) -> Roots {
+        Roots::new(self.search_dirs().into_iter().map(|dir| dir.clone()).collect())
+    }
+
+    /// If images can be processed while the search for them is still going on.
+    ///
+    /// `--exact` groups files by size and `--limit` picks the first images in path order, 
+    /// so both need every path up front, and `--files-from` lists are read all at once anyway.
+    pub fn stream_discovery(&self) -> bool {
+        self.files_from.is_none() && !self.exact && self.limit == 0
+    }
+
+    pub fn reference_mode(&self) -> bool {
+        !self.reference_dirs.is_empty()
+    }
+
+    /// If the images can be decoded again while matching, i.e. their hashes weren't loaded 
+    /// with `--load-hashes` or `--import-index`, which promise not to touch the images.
+    pub fn can_decode(&self) -> bool {
+        self.load_hashes.is_none() && self.import_index.is_empty()
+    }
+
+    /// The search root `path` was found under, for cross-root matching.
+    ///
+    /// With a single search directory, each of its immediate subdirectories is a root.
+    pub fn root_of(&self, path: &Path) -> Path {
+        if let Some(dir) = self.reference_dirs.iter().find(|dir| dir.is_ancestor_of(path)) {
+            return dir.clone();
+        }
+
+        if self.dirs.len() > 1 {
+            let roots = self.roots();
+            return roots.root_of(path).unwrap_or(self.dir()).clone();
+        }
+
+        // Images directly in the search directory belong to the search directory itself
+        match path.path_relative_from(self.dir()) {
+            Some(ref rel) if rel.components().count() > 1 => 
+                self.dir().join(rel.components().next().unwrap()),
+            _ => self.dir().clone(),
+        }
+    }
+
+    pub fn role_of(&self, path: &Path) -> Role {
+        if self.reference_dirs.iter().any(|dir| dir.is_ancestor_of(path)) {
+            Role::Reference
+        } else {
+            Role::Candidate
+        }
+    }
+}
+
+/// Where to read the list of files to process from, instead of searching for them.
+#[deriving(Clone)]
+pub enum PathList {
+    Stdin,
+    File(Path),
+}
+
+impl PathList {
+    pub fn describe(&self) -> String {
+        match *self {
+            PathList::Stdin => "-".to_string(),
+            PathList::File(ref path) => path.display().to_string(),
+        }
+    }
+}
+
+/// The role an image plays in reference mode.
+#[deriving(PartialEq, Eq, Copy, Clone, Show)]
+pub enum Role {
+    /// An image that should be matched against the reference library.
+    Candidate,
+    /// An image from the trusted reference library.
+    Reference,
+}
+
+/// The search roots, for showing each path relative to the root it was found under.
+#[deriving(Clone)]
+pub struct Roots {
+    dirs: Vec<Path>,
+}
+
+impl Roots {
+    pub fn new(dirs: Vec<Path>) -> Roots {
+        Roots { dirs: dirs }
+    }
+
+    /// The innermost root `path` is under, in case roots are nested.
+    pub fn root_of(&self, path: &Path) -> Option<&Path> {
+        self.dirs.iter()
+            .filter(|dir| dir.is_ancestor_of(path))
+            .max_by(|dir| dir.components().count())
+    }
+
+    /// `path` relative to its root, or as-is if it isn't under any.
+    pub fn relative(&self, path: &Path) -> Path {
+        self.root_of(path)
+            .and_then(|root| path.path_relative_from(root))
+            .unwrap_or(path.clone())
+    }
+
+    /// `path` relative to its root, labeled with the root if there's more than one.
+    pub fn display(&self, path: &Path) -> String {
+        match self.root_of(path) {
+            Some(root) if self.dirs.len() > 1 => 
+                format!("[{}] {}", root.display(), self.relative(path).display()),
+            _ => self.relative(path).display().to_string(),
+        }
+    }
+
+    /// Add `path` relative to its root under `key`, and its root under `root_key`.
+    pub fn insert_json(&self, json: &mut BTreeMap<String, Json>, key: &str, root_key: &str, 
+                       path: &Path) {
+        json_insert!(json, key, self.relative(path).display().to_string());
+        json_insert!(json, root_key, self.root_of(path).map(|root| root.display().to_string()));
+    }
 }
 
 impl Show for ProgramSettings {
     fn fmt(&self, fmt: &mut Formatter) -> FormatResult {
         try!(writeln!(fmt, "Threads: {}", self.threads));
-        try!(writeln!(fmt, "Directory: {}", &self.dir.display()));
+        for dir in self.dirs.iter() {
+            try!(writeln!(fmt, "Directory: {}", dir.display()));
+        }
+
+        for dir in self.reference_dirs.iter() {
+            try!(writeln!(fmt, "Reference directory: {}", dir.display()));
+        }
+
         try!(writeln!(fmt, "Recursive: {}", self.recurse));
+
+        if let Some(max_depth) = self.max_depth {
+            try!(writeln!(fmt, "Maximum depth: {}", max_depth));
+        }
+
+        try!(writeln!(fmt, "Cross-root only: {}", self.cross_root));
         try!(writeln!(fmt, "Extensions: {}", self.exts.as_slice()));
         try!(writeln!(fmt, "Hash size: {}", self.hash_size));
-        try!(writeln!(fmt, "Threshold: {0:.2}%", self.threshold * 100f32));
-        writeln!(fmt, "Fast: {}", self.fast)
+        try!(writeln!(fmt, "Threshold: {0:.2}% ({1})", 
+                      self.threshold.ratio(self.hash_size) * 100f32,
+                      self.threshold.describe_bits(self.hash_size)));
+
+        if let Some(tolerance) = self.aspect_tolerance {
+            try!(writeln!(fmt, "Aspect ratio tolerance: {0:.2}%", tolerance * 100f32));
+        }
+
+        if let Some(min_scale) = self.min_scale {
+            try!(writeln!(fmt, "Minimum scale: {}", min_scale));
+        }
+
+        if let Some(max_scale) = self.max_scale {
+            try!(writeln!(fmt, "Maximum scale: {}", max_scale));
+        }
+
+        try!(writeln!(fmt, "Fast: {}", self.fast));
+
+        if !self.keep.is_empty() {
+            try!(writeln!(fmt, "Keep: {}", self.keep_names().connect(", ")));
+        }
+
+        writeln!(fmt, "Exact copies first: {}", self.exact)
     }
 }
 
@@ -116,24 +551,145 @@ impl ToJson for ProgramSettings {
     fn to_json(&self) -> Json {
         let mut my_json = BTreeMap::new();
         json_insert!(my_json, "threads", self.threads);
-        json_insert!(my_json, "dir", self.dir.display().to_string());
+        json_insert!(my_json, "dir", self.dir().display().to_string());
+        json_insert!(my_json, "dirs", display_all(&*self.dirs));
+        json_insert!(my_json, "reference_dirs", display_all(&*self.reference_dirs));
         json_insert!(my_json, "recurse", self.recurse);
+        json_insert!(my_json, "cross_root", self.cross_root);
         json_insert!(my_json, "exts", self.exts.as_slice());
+        json_insert!(my_json, "sniff", self.sniff);
+        json_insert!(my_json, "include", self.include.as_slice());
+        json_insert!(my_json, "exclude", self.exclude.as_slice());
+        json_insert!(my_json, "symlinks", self.symlinks.name());
+        json_insert!(my_json, "files_from", self.files_from.as_ref().map(|list| list.describe()));
+        json_insert!(my_json, "null", self.null_separated);
+        json_insert!(my_json, "max_depth", self.max_depth);
+        json_insert!(my_json, "min_size", self.min_size);
+        json_insert!(my_json, "max_size", self.max_size);
+        json_insert!(my_json, "newer_than", self.newer_than);
+        json_insert!(my_json, "older_than", self.older_than);
+        json_insert!(my_json, "min_dimensions", self.min_dimensions.map(describe_dimensions));
+        json_insert!(my_json, "max_dimensions", self.max_dimensions.map(describe_dimensions));
         json_insert!(my_json, "hash_size", self.hash_size);
-        json_insert!(my_json, "threshold", self.threshold);
+        json_insert!(my_json, "threshold", self.threshold.ratio(self.hash_size));
+        json_insert!(my_json, "threshold_bits", self.threshold.bits(self.hash_size));
+        json_insert!(my_json, "aspect_tolerance", self.aspect_tolerance);
+        json_insert!(my_json, "min_scale", self.min_scale);
+        json_insert!(my_json, "max_scale", self.max_scale);
         json_insert!(my_json, "fast", self.fast);
+        json_insert!(my_json, "exact", self.exact);
         json_insert!(my_json, "limit", self.limit);
+        json_insert!(my_json, "keep", self.keep_names());
+        json_insert!(my_json, "cache", self.cache.as_ref().map(|path| path.display().to_string()));
+        json_insert!(my_json, "xattr", self.xattr);
+        json_insert!(my_json, "journal", self.journal.as_ref().map(|path| path.display().to_string()));
+        json_insert!(my_json, "resume", self.resume);
+        json_insert!(my_json, "ignore_list", self.ignore_list.display().to_string());
+        json_insert!(my_json, "save_hashes", self.save_hashes.as_ref().map(|path| path.display().to_string()));
+        json_insert!(my_json, "load_hashes", self.load_hashes.as_ref().map(|path| path.display().to_string()));
+        json_insert!(my_json, "update_index", self.update_index.as_ref().map(|path| path.display().to_string()));
+        json_insert!(my_json, "export_index", self.export_index.as_ref().map(|path| path.display().to_string()));
+        json_insert!(my_json, "import_index", display_all(&*self.import_index));
+        json_insert!(my_json, "index_label", self.index_label);
+        json_insert!(my_json, "query", self.query);
+        json_insert!(my_json, "top", self.top);
 
         Json::Object(my_json)
     }
 }
 
-#[deriving(Copy)]
+#[deriving(Copy, Clone, PartialEq)]
 pub struct HashSettings {
     pub hash_size: u32,
     pub fast: bool,
 }
 
+/// The maximum difference between two hashes for their images to be considered similar.
+#[deriving(PartialEq, Copy, Clone, Show)]
+pub enum Threshold {
+    /// A fraction of the hash bits that must differ for images to be unique.
+    Ratio(f32),
+    /// The maximum Hamming distance for images to be similar.
+    Bits(uint),
+}
+
+const DCT_DEFAULT_RATIO: f32 = 0.03;
+const MEAN_DEFAULT_RATIO: f32 = 0.02;
+
+impl Threshold {
+    /// Roughly 3% of the bits for DCT hashes and 2% for the noisier mean ("fast") hashes,
+    /// but never less than one bit so small hashes still tolerate recompression.
+    ///
+    /// These ratios are a rule of thumb, not measured; they keep the old default of 3%.
+    pub fn default_for(hash_size: u32, fast: bool) -> Threshold {
+        let ratio = if fast { MEAN_DEFAULT_RATIO } else { DCT_DEFAULT_RATIO };
+        let bits = (hash_size * hash_size) as f32 * ratio;
+
+        Threshold::Bits(max(1, bits as uint))
+    }
+
+    pub fn is_within(&self, left: &ImageHash, right: &ImageHash) -> bool {
+        match *self {
+            Threshold::Ratio(ratio) => left.dist_ratio(right) < ratio,
+            Threshold::Bits(bits) => left.dist(right) <= bits,
+        }
+    }
+
+    /// The threshold as a fraction of the bits in a hash of the given size.
+    pub fn ratio(&self, hash_size: u32) -> f32 {
+        match *self {
+            Threshold::Ratio(ratio) => ratio,
+            Threshold::Bits(bits) => bits as f32 / (hash_size * hash_size) as f32,
+        }
+    }
+
+    /// The threshold as a number of bits in a hash of the given size.
+    pub fn bits(&self, hash_size: u32) -> f32 {
+        match *self {
+            Threshold::Ratio(ratio) => ratio * (hash_size * hash_size) as f32,
+            Threshold::Bits(bits) => bits as f32,
+        }
+    }
+
+    pub fn describe_bits(&self, hash_size: u32) -> String {
+        match *self {
+            Threshold::Ratio(_) => format!("less than {:.2} bits", self.bits(hash_size)),
+            Threshold::Bits(bits) => format!("at most {} bits", bits),
+        }
+    }
+}
+
+/// Checks on the dimensions of two images that must pass before their hashes are trusted,
+/// since every image is squashed to a square before hashing.
+#[deriving(Copy, Clone)]
+pub struct DimensionGuards {
+    pub aspect_tolerance: Option<f32>,
+    pub min_scale: Option<f32>,
+    pub max_scale: Option<f32>,
+}
+
+impl DimensionGuards {
+    pub fn check(&self, original: &Image, other: &Image) -> Option<GuardFailure> {
+        if let Some(tolerance) = self.aspect_tolerance {
+            let (orig_aspect, other_aspect) = (original.aspect_ratio(), other.aspect_ratio());
+            let diff = (orig_aspect - other_aspect).abs() / orig_aspect.max(other_aspect);
+
+            if diff > tolerance {
+                return Some(GuardFailure::AspectRatio(orig_aspect, other_aspect));
+            }
+        }
+
+        let scale = original.scale_of(other);
+
+        if self.min_scale.map_or(false, |min| scale < min) 
+            || self.max_scale.map_or(false, |max| scale > max) {
+            return Some(GuardFailure::Scale(scale));
+        }
+
+        None
+    }
+}
+
 #[deriving(PartialEq, Eq, Copy, Clone)]
 pub enum JsonSettings {
     NoJson,
@@ -148,6 +704,11 @@ impl JsonSettings {
     }
 }
 
+const DEFAULT_CACHE: &'static str = ".img_dup_cache";
+const DEFAULT_JOURNAL: &'static str = ".img_dup_journal";
+const DEFAULT_IGNORE_LIST: &'static str = "img_dup_ignored.txt";
+const LINKS_FILE: &'static str = ".img_dup_links";
+
 pub fn parse_args(args: &[String]) -> ProgramSettings {
     let settings_opts = ProgramSettings::opts();
     
@@ -159,31 +720,103 @@ pub fn parse_args(args: &[String]) -> ProgramSettings {
 
     let exts_default = vec!("jpeg", "jpg", "png");
 
-    let dir = dir_arg(opts, "dir", os::getcwd().unwrap());
+    let mut dirs = dirs_arg(opts, "dir");
+
+    if dirs.is_empty() {
+        dirs.push(os::getcwd().unwrap());
+    }
+
+    let dir = dirs[0].clone();
+
+    let hash_size = uint_arg(opts, "hash-size", 8) as u32;
+    let fast = opts.opt_present("fast");
+
+    assert!(!opts.opt_present("update-index") 
+            || !(opts.opt_present("load-hashes") || opts.opt_present("import-index") 
+                 || opts.opt_present("query")),
+            "--update-index cannot be combined with --load-hashes, --import-index or --query");
+
+    assert!(!(opts.opt_present("load-hashes") && opts.opt_present("import-index")),
+            "--load-hashes cannot be combined with --import-index");
+
+    assert!(!(opts.opt_present("threshold") && opts.opt_present("threshold-bits")),
+            "--threshold cannot be combined with --threshold-bits");
+
+    let min_scale = opt_f32_arg(opts, "min-scale");
+    let max_scale = opt_f32_arg(opts, "max-scale");
+
+    if let (Some(min), Some(max)) = (min_scale, max_scale) {
+        assert!(min <= max, "--min-scale ({}) cannot be more than --max-scale ({})", min, max);
+    }
 
     ProgramSettings {
         threads: uint_arg(opts, "threads", os::num_cpus()),
-        dir: dir.clone(),
+        dirs: dirs,
+        reference_dirs: dirs_arg(opts, "reference"),
         recurse: opts.opt_present("recurse"),
-        hash_size: uint_arg(opts, "hash-size", 8) as u32,
-        threshold: pos_f32_arg(opts, "threshold", 3f32) / 100f32,
-        fast: opts.opt_present("fast"),
+        max_depth: opt_uint_arg(opts, "max-depth"),
+        cross_root: opts.opt_present("cross-root"),
+        hash_size: hash_size,
+        threshold: threshold_arg(opts, Threshold::default_for(hash_size, fast)),
+        default_threshold: !opts.opt_present("threshold") && !opts.opt_present("threshold-bits"),
+        aspect_tolerance: opt_f32_arg(opts, "aspect-tolerance").map(|tol| tol / 100f32),
+        min_scale: min_scale,
+        max_scale: max_scale,
+        fast: fast,
+        exact: opts.opt_present("exact"),
         exts: exts_args(opts, "ext", exts_default),
+        sniff: opts.opt_present("sniff"),
+        include: opts.opt_strs("include"),
+        exclude: opts.opt_strs("exclude"),
+        files_from: path_list_arg(opts, "files-from", &dir),
+        symlinks: symlinks_arg(opts, "symlinks"),
+        null_separated: opts.opt_present("null"),
+        min_size: size_arg(opts, "min-size"),
+        max_size: size_arg(opts, "max-size"),
+        newer_than: date_arg(opts, "newer-than"),
+        older_than: date_arg(opts, "older-than"),
+        min_dimensions: dimensions_arg(opts, "min-dimensions"),
+        max_dimensions: dimensions_arg(opts, "max-dimensions"),
         outfile: outfile_arg(opts, "outfile", &dir),
         dup_only: opts.opt_present("dup-only"),
+        keep: keep_args(opts, "keep", &dir),
         limit: uint_arg(opts, "limit", 0),
         json: json_arg(opts, "json", JsonSettings::NoJson),
+        cache: cache_arg(opts, "cache", &dir),
+        xattr: opts.opt_present("xattr"),
+        journal: journal_arg(opts, &dir),
+        resume: opts.opt_present("resume"),
+        ignore_list: outfile_arg(opts, "ignore-list", &dir)
+            .unwrap_or_else(|| dir.join(DEFAULT_IGNORE_LIST)),
+        list_ignored: opts.opt_present("list-ignored"),
+        prune_ignored: opts.opt_present("prune-ignored"),
+        save_hashes: outfile_arg(opts, "save-hashes", &dir),
+        load_hashes: outfile_arg(opts, "load-hashes", &dir),
+        update_index: outfile_arg(opts, "update-index", &dir),
+        export_index: outfile_arg(opts, "export-index", &dir),
+        import_index: files_arg(opts, "import-index", &dir),
+        index_label: opts.opt_str("index-label"),
+        query: opts.opt_str("query"),
+        top: uint_arg(opts, "top", 10),
 		gui: opts.opt_present("gui"), 
     }    
 }
 
-fn dir_arg(args: &Matches, arg: &str, default: Path) -> Path {
-    let dir = args.opt_str(arg).map_or(default, |path| Path::new(path) );
+fn dirs_arg(args: &Matches, arg: &str) -> Vec<Path> {
+    args.opt_strs(arg).into_iter()
+        .map(|path| {
+            let dir = Path::new(path);
 
-    assert!(dir.is_dir(), "Value passed to {} is not a directory: {}", 
-            arg, dir.display());
+            assert!(dir.is_dir(), "Value passed to {} is not a directory: {}",
+                    arg, dir.display());
+
+            dir
+        })
+        .collect()
+}
 
-    dir
+fn display_all(paths: &[Path]) -> Vec<String> {
+    paths.iter().map(|path| path.display().to_string()).collect()
 }
 
 fn outfile_arg(args: &Matches, arg: &str, dir: &Path) -> Option<Path> {
@@ -197,6 +830,32 @@ fn outfile_arg(args: &Matches, arg: &str, dir: &Path) -> Option<Path> {
     })
 }
 
+fn path_list_arg(args: &Matches, arg: &str, dir: &Path) -> Option<PathList> {
+    match args.opt_str(arg) {
+        Some(ref list) if &**list == "-" => Some(PathList::Stdin),
+        Some(_) => outfile_arg(args, arg, dir).map(PathList::File),
+        None => None,
+    }
+}
+
+fn files_arg(args: &Matches, arg: &str, dir: &Path) -> Vec<Path> {
+    args.opt_strs(arg).into_iter()
+        .map(|path| dir.join(path))
+        .collect()
+}
+
+fn cache_arg(args: &Matches, arg: &str, dir: &Path) -> Option<Path> {
+    if !args.opt_present(arg) { return None; }
+
+    outfile_arg(args, arg, dir).or_else(|| Some(dir.join(DEFAULT_CACHE)))
+}
+
+fn journal_arg(args: &Matches, dir: &Path) -> Option<Path> {
+    if !args.opt_present("journal") && !args.opt_present("resume") { return None; }
+
+    outfile_arg(args, "journal", dir).or_else(|| Some(dir.join(DEFAULT_JOURNAL)))
+}
+
 fn uint_arg(args: &Matches, arg: &str, default: uint) -> uint {
     let val = args.opt_str(arg).map_or(default, |arg_str|   
                 arg_str.parse::<uint>().unwrap()
@@ -205,6 +864,58 @@ fn uint_arg(args: &Matches, arg: &str, default: uint) -> uint {
     val
 }
 
+fn opt_uint_arg(args: &Matches, arg: &str) -> Option<uint> {
+    args.opt_str(arg).map(|arg_str| arg_str.parse::<uint>().unwrap())
+}
+
+/// A number of bytes, optionally followed by K, M or G.
+fn size_arg(args: &Matches, arg: &str) -> Option<u64> {
+    args.opt_str(arg).map(|size| {
+        let shift = match size.chars().last() {
+            Some('k') | Some('K') => 10u,
+            Some('m') | Some('M') => 20,
+            Some('g') | Some('G') => 30,
+            _ => 0,
+        };
+
+        let digits = if shift > 0 { size.slice_to(size.len() - 1) } else { &*size };
+
+        // Anything that doesn't fit in 64 bits once shifted is as invalid as any other typo
+        digits.parse::<u64>()
+            .and_then(|num| if num > u64::MAX >> shift { None } else { Some(num << shift) })
+            .unwrap_or_else(|| panic!("Value of {} must be a number of bytes, e.g. 100K: {}", arg, size))
+    })
+}
+
+/// A date as YYYY-MM-DD, in ms since the epoch at midnight UTC.
+fn date_arg(args: &Matches, arg: &str) -> Option<u64> {
+    args.opt_str(arg).map(|date| {
+        let tm = strptime(&*date, "%Y-%m-%d")
+            .unwrap_or_else(|_| panic!("Value of {} must be a date as YYYY-MM-DD: {}", arg, date));
+
+        let sec = tm.to_timespec().sec;
+
+        assert!(sec >= 0, "Value of {} must not be before 1970-01-01: {}", arg, date);
+
+        sec as u64 * 1000
+    })
+}
+
+fn dimensions_arg(args: &Matches, arg: &str) -> Option<(u32, u32)> {
+    args.opt_str(arg).map(|dims| {
+        let mut parts = dims.splitn(1, 'x').map(|part| part.trim().parse::<u32>());
+
+        match (parts.next(), parts.next()) {
+            (Some(Some(width)), Some(Some(height))) => (width, height),
+            _ => panic!("Value of {} must be a width and height, e.g. 64x64: {}", arg, dims),
+        }
+    })
+}
+
+fn describe_dimensions((width, height): (u32, u32)) -> String {
+    format!("{}x{}", width, height)
+}
+
 fn pos_f32_arg(args: &Matches, arg: &str, default: f32) -> f32 {
     let val = args.opt_str(arg)
         .map_or(default, |arg_str|
@@ -217,6 +928,26 @@ fn pos_f32_arg(args: &Matches, arg: &str, default: f32) -> f32 {
     val
 }
 
+fn threshold_arg(args: &Matches, default: Threshold) -> Threshold {
+    if args.opt_present("threshold-bits") {
+        Threshold::Bits(uint_arg(args, "threshold-bits", 0))
+    } else if args.opt_present("threshold") {
+        Threshold::Ratio(pos_f32_arg(args, "threshold", 0f32) / 100f32)
+    } else {
+        default
+    }
+}
+
+fn opt_f32_arg(args: &Matches, arg: &str) -> Option<f32> {
+    args.opt_str(arg).map(|arg_str| {
+        let val = arg_str.parse::<f32>().unwrap();
+
+        assert!(val >= 0f32, "Value of {} must not be negative", arg);
+
+        val
+    })
+}
+
 fn exts_args<'a>(args: &'a Matches, arg: &'a str, default: Vec<&'static str>) -> Vec<String> {
     if args.opt_present(arg) {
         args.opt_strs(arg)
@@ -225,6 +956,25 @@ fn exts_args<'a>(args: &'a Matches, arg: &'a str, default: Vec<&'static str>) ->
     }
 }
 
+/// Relative `prefer:` directories are resolved against `dir`, like the other paths we take.
+fn keep_args(args: &Matches, arg: &str, dir: &Path) -> Vec<KeepPolicy> {
+    args.opt_strs(arg).iter()
+        .map(|policy| KeepPolicy::parse(&**policy)
+             .unwrap_or_else(|| panic!("Unknown value passed to {}: {}", arg, policy)))
+        .map(|policy| match policy {
+            KeepPolicy::Prefer(ref path) if path.is_relative() => KeepPolicy::Prefer(dir.join(path)),
+            policy => policy,
+        })
+        .collect()
+}
+
+fn symlinks_arg(args: &Matches, arg: &str) -> SymlinkPolicy {
+    args.opt_str(arg).map_or(SymlinkPolicy::Follow, |policy| 
+        SymlinkPolicy::parse(&*policy)
+            .unwrap_or_else(|| panic!("Unknown value passed to {}: {}", arg, policy))
+    )
+}
+
 fn json_arg(args: &Matches, arg: &str, default: JsonSettings) -> JsonSettings {
     if args.opt_present(arg) {
         match args.opt_str(arg) {
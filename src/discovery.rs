@@ -0,0 +1,1016 @@
+use config::{PathList, ProgramSettings, Roots};
+use links::LinkRecord;
+
+use glob::{Pattern, MatchOptions};
+
+use image::ImageFormat;
+
+use serialize::json::{Json, ToJson};
+
+use std::ascii::AsciiExt;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{stdio, BufferedReader, FileStat, FileType, IoResult, Seek, SeekCur, SeekSet};
+use std::io::fs::{mod, File, PathExtensions};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Relaxed};
+use std::thread::Thread;
+
+/// How many bytes we need to recognize any of the formats.
+const HEADER_LEN: uint = 12;
+
+/// An image format we can recognize by its first bytes, and decode.
+#[deriving(Copy, Clone, PartialEq, Show)]
+pub enum Format {
+    Png,
+    Jpeg,
+    Gif,
+    WebP,
+}
+
+impl Format {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Format::Png => "PNG",
+            Format::Jpeg => "JPEG",
+            Format::Gif => "GIF",
+            Format::WebP => "WebP",
+        }
+    }
+
+    /// The extensions files of this format usually have.
+    pub fn exts(&self) -> &'static [&'static str] {
+        static PNG: [&'static str; 1] = ["png"];
+        static JPEG: [&'static str; 4] = ["jpg", "jpeg", "jpe", "jfif"];
+        static GIF: [&'static str; 1] = ["gif"];
+        static WEBP: [&'static str; 1] = ["webp"];
+
+        match *self {
+            Format::Png => &PNG,
+            Format::Jpeg => &JPEG,
+            Format::Gif => &GIF,
+            Format::WebP => &WEBP,
+        }
+    }
+
+    pub fn image_format(&self) -> ImageFormat {
+        match *self {
+            Format::Png => ImageFormat::PNG,
+            Format::Jpeg => ImageFormat::JPEG,
+            Format::Gif => ImageFormat::GIF,
+            Format::WebP => ImageFormat::WEBP,
+        }
+    }
+
+    fn from_header(header: &[u8]) -> Option<Format> {
+        if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Format::Png)
+        } else if header.starts_with(b"\xff\xd8\xff") {
+            Some(Format::Jpeg)
+        } else if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+            Some(Format::Gif)
+        } else if header.starts_with(b"RIFF") && header.slice(8, 12) == b"WEBP".as_slice() {
+            Some(Format::WebP)
+        } else {
+            None
+        }
+    }
+
+    fn has_ext_of(&self, path: &Path) -> bool {
+        path.extension_str().map_or(false, |ext| any_ext_matches(self.exts(), ext))
+    }
+
+    /// If any of this format's usual extensions are in `exts`.
+    fn is_wanted(&self, exts: &[&str]) -> bool {
+        self.exts().iter().any(|&ext| any_ext_matches(exts, ext))
+    }
+}
+
+/// Recognize the format of the file at `path` by its first bytes.
+pub fn sniff(path: &Path) -> Option<Format> {
+    File::open(path).ok().and_then(|mut file| sniff_reader(&mut file))
+}
+
+/// Recognize the format of an image by reading its first bytes from `reader`.
+pub fn sniff_reader(reader: &mut Reader) -> Option<Format> {
+    reader.read_exact(HEADER_LEN).ok().and_then(|header| Format::from_header(&*header))
+}
+
+/// Read the width and height of the image at `path` from its header, without decoding it.
+///
+/// Returns `None` if the format isn't recognized or the header is cut short.
+pub fn read_dimensions(path: &Path) -> Option<(u32, u32)> {
+    File::open(path).ok().and_then(|mut file| dimensions_of(&mut file))
+}
+
+/// Read the width and height of the image that `reader` starts with.
+fn dimensions_of<R: Reader + Seek>(reader: &mut R) -> Option<(u32, u32)> {
+    let header = match reader.read_exact(HEADER_LEN) {
+        Ok(header) => header,
+        Err(_) => return None,
+    };
+
+    let dims = match Format::from_header(&*header) {
+        Some(Format::Png) => png_dimensions(reader),
+        Some(Format::Jpeg) => jpeg_dimensions(reader),
+        Some(Format::Gif) => Ok(Some(gif_dimensions(&*header))),
+        Some(Format::WebP) => webp_dimensions(reader),
+        None => Ok(None),
+    };
+
+    dims.ok().and_then(|dims| dims)
+}
+
+/// The header is followed by the length of the IHDR chunk, which always comes first.
+fn png_dimensions<R: Reader>(file: &mut R) -> IoResult<Option<(u32, u32)>> {
+    // The chunk type
+    try!(file.read_exact(4));
+
+    let width = try!(file.read_be_u32());
+    let height = try!(file.read_be_u32());
+
+    Ok(Some((width, height)))
+}
+
+/// The dimensions are in the frame header, which may come after any number of other segments.
+fn jpeg_dimensions<R: Reader + Seek>(file: &mut R) -> IoResult<Option<(u32, u32)>> {
+    // Just after the start of image marker
+    try!(file.seek(2, SeekSet));
+
+    loop {
+        if try!(file.read_byte()) != 0xFF { return Ok(None); }
+
+        // Markers may be padded with any number of 0xFF bytes
+        let mut marker = try!(file.read_byte());
+        while marker == 0xFF { marker = try!(file.read_byte()); }
+
+        match marker {
+            // Markers without a segment
+            0x01 | 0xD0...0xD8 => continue,
+            // End of image or start of scan, with no frame header before it
+            0xD9 | 0xDA => return Ok(None),
+            _ => (),
+        }
+
+        let len = try!(file.read_be_u16()) as i64;
+
+        match marker {
+            // Start of frame, except for the DHT, JPG and DAC markers sharing the range
+            0xC0...0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => {
+                // Sample precision
+                try!(file.read_byte());
+
+                let height = try!(file.read_be_u16()) as u32;
+                let width = try!(file.read_be_u16()) as u32;
+
+                return Ok(Some((width, height)));
+            },
+            _ => try!(file.seek(len - 2, SeekCur)),
+        }
+    }
+}
+
+fn gif_dimensions(header: &[u8]) -> (u32, u32) {
+    let width = header[6] as u32 | (header[7] as u32 << 8);
+    let height = header[8] as u32 | (header[9] as u32 << 8);
+
+    (width, height)
+}
+
+/// The first chunk after the RIFF header is one of three kinds, each storing the size differently.
+fn webp_dimensions<R: Reader>(file: &mut R) -> IoResult<Option<(u32, u32)>> {
+    let chunk = try!(file.read_exact(4));
+    // The chunk size
+    try!(file.read_le_u32());
+
+    if &*chunk == b"VP8 ".as_slice() {
+        // The frame tag and start code
+        try!(file.read_exact(6));
+
+        let width = try!(file.read_le_u16()) & 0x3FFF;
+        let height = try!(file.read_le_u16()) & 0x3FFF;
+
+        Ok(Some((width as u32, height as u32)))
+    } else if &*chunk == b"VP8L".as_slice() {
+        // The signature byte
+        try!(file.read_byte());
+
+        let bits = try!(file.read_le_u32());
+
+        Ok(Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1)))
+    } else if &*chunk == b"VP8X".as_slice() {
+        // The feature flags
+        try!(file.read_exact(4));
+
+        let width = try!(file.read_le_uint_n(3)) as u32 + 1;
+        let height = try!(file.read_le_uint_n(3)) as u32 + 1;
+
+        Ok(Some((width, height)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Something odd found during discovery that doesn't stop the image from being processed.
+pub enum Warning {
+    /// The file's content is in a different format than its extension says.
+    ExtensionMismatch(Path, Format),
+    /// A symbolic link to an image, which wasn't processed. Holds the link and its target.
+    Symlink(Path, Path),
+}
+
+unsafe impl Send for Warning {}
+
+impl Warning {
+    pub fn path(&self) -> &Path {
+        match *self {
+            Warning::ExtensionMismatch(ref path, _) => path,
+            Warning::Symlink(ref path, _) => path,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Warning::ExtensionMismatch(..) => "extension_mismatch",
+            Warning::Symlink(..) => "symlink",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match *self {
+            Warning::ExtensionMismatch(ref path, format) => match path.extension_str() {
+                Some(ext) => format!("Extension is .{}, but the content is {}", ext, format.name()),
+                None => format!("No extension, but the content is {}", format.name()),
+            },
+            Warning::Symlink(_, ref target) => 
+                format!("Symbolic link to {}, not processed", target.display()),
+        }
+    }
+
+    pub fn to_json(&self, roots: &Roots) -> Json {
+        let mut json = BTreeMap::new();
+
+        roots.insert_json(&mut json, "path", "root", self.path());
+        json_insert!(json, "warning", self.kind());
+        json_insert!(json, "message", self.message());
+
+        match *self {
+            Warning::ExtensionMismatch(_, format) => json_insert!(json, "format", format.name()),
+            Warning::Symlink(_, ref target) => json_insert!(json, "target", target.display().to_string()),
+        };
+
+        Json::Object(json)
+    }
+
+    pub fn write_self(&self, out: &mut Writer, roots: &Roots) -> IoResult<()> {
+        writeln!(out, "Image: {}\n {}", roots.display(self.path()), self.message())
+    }
+}
+
+/// The images found in the search directories.
+pub struct Discovered {
+    pub paths: Vec<Path>,
+    pub warnings: Vec<Warning>,
+    /// Paths that are the same file as one in `paths`, and aren't processed themselves.
+    pub linked: Vec<LinkedFiles>,
+}
+
+unsafe impl Send for Discovered {}
+
+/// Paths that all lead to the same file on disk, hard links or symbolic links followed to it. 
+///
+/// Deleting one of them frees nothing, so only `path` is hashed and matched against the rest.
+pub struct LinkedFiles {
+    pub path: Path,
+    pub links: Vec<Path>,
+}
+
+unsafe impl Send for LinkedFiles {}
+
+impl LinkedFiles {
+    pub fn to_json(&self, roots: &Roots) -> Json {
+        let mut json = BTreeMap::new();
+
+        roots.insert_json(&mut json, "path", "root", &self.path);
+
+        let links: Vec<Json> = self.links.iter()
+            .map(|link| {
+                let mut link_json = BTreeMap::new();
+                roots.insert_json(&mut link_json, "path", "root", link);
+                Json::Object(link_json)
+            })
+            .collect();
+
+        json_insert!(json, "links", links);
+
+        Json::Object(json)
+    }
+
+    pub fn write_self(&self, out: &mut Writer, roots: &Roots) -> IoResult<()> {
+        try!(writeln!(out, "File: {}", roots.display(&self.path)));
+        try!(out.write_line("Also linked as:"));
+
+        for link in self.links.iter() {
+            try!(writeln!(out, "{}", roots.display(link)));
+        }
+
+        Ok(())
+    }
+}
+
+/// The device and inode of the file at `path`, if it can be read and the platform has them.
+///
+/// On Windows, the inode is always 0, which would make every file look like the same one.
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    match fs::stat(path) {
+        Ok(stat) if stat.unstable.inode != 0 => Some((stat.unstable.device, stat.unstable.inode)),
+        _ => None,
+    }
+}
+
+/// The files found so far, to drop paths found twice and group paths to the same file.
+struct FoundFiles {
+    paths: HashSet<Path>,
+    /// The first path found to each file, by device and inode, and its entry in `linked` if any.
+    files: HashMap<(u64, u64), (Path, Option<uint>)>,
+    linked: Vec<LinkedFiles>,
+}
+
+impl FoundFiles {
+    fn new() -> FoundFiles {
+        FoundFiles {
+            paths: HashSet::new(),
+            files: HashMap::new(),
+            linked: Vec::new(),
+        }
+    }
+
+    /// If neither `path` nor another path to the same file was found before.
+    ///
+    /// Files that can't be read are kept, so their errors are reported when they're processed.
+    fn is_new(&mut self, path: &Path) -> bool {
+        // Reference directories may be nested inside the search directory
+        if !self.paths.insert(path.clone()) { return false; }
+
+        let id = match file_id(path) {
+            Some(id) => id,
+            // Only the path is left to tell files apart
+            None => return true,
+        };
+
+        if let Some(file) = self.files.get_mut(&id) {
+            let (ref first, ref mut linked_idx) = *file;
+
+            match *linked_idx {
+                Some(idx) => self.linked[idx].links.push(path.clone()),
+                None => {
+                    *linked_idx = Some(self.linked.len());
+                    self.linked.push(LinkedFiles {
+                        path: first.clone(),
+                        links: vec![path.clone()],
+                    });
+                },
+            }
+
+            return false;
+        }
+
+        self.files.insert(id, (path.clone(), None));
+
+        true
+    }
+}
+
+/// A glob pattern from `--include` or `--exclude`.
+struct Glob {
+    pattern: Pattern,
+    /// Patterns containing a `/` match the whole path relative to the search directory;
+    /// others match the file or directory name at any depth, as in `.gitignore`.
+    anchored: bool,
+}
+
+impl Glob {
+    fn new(pattern: &str) -> Glob {
+        // A trailing slash is allowed for directories, but names never have one
+        let pattern = pattern.trim_right_matches('/');
+
+        Glob {
+            pattern: Pattern::new(pattern.trim_left_matches('/')),
+            anchored: pattern.contains("/"),
+        }
+    }
+
+    fn matches(&self, rel_path: &Path) -> bool {
+        let options = MatchOptions {
+            case_sensitive: true,
+            // So `*` doesn't match across directories
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+
+        if self.anchored {
+            self.pattern.matches_with(&*rel_path.display().to_string(), &options)
+        } else {
+            rel_path.filename_str().map_or(false, |name| self.pattern.matches_with(name, &options))
+        }
+    }
+}
+
+/// What to do with symbolic links found while walking.
+#[deriving(Copy, Clone, PartialEq, Show)]
+pub enum SymlinkPolicy {
+    /// Leave them out.
+    Skip,
+    /// Treat them as the file or directory they point to.
+    Follow,
+    /// List links to images as warnings, without processing them.
+    Report,
+}
+
+impl SymlinkPolicy {
+    pub fn parse(policy: &str) -> Option<SymlinkPolicy> {
+        match policy {
+            "skip" => Some(SymlinkPolicy::Skip),
+            "follow" => Some(SymlinkPolicy::Follow),
+            "report" => Some(SymlinkPolicy::Report),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SymlinkPolicy::Skip => "skip",
+            SymlinkPolicy::Follow => "follow",
+            SymlinkPolicy::Report => "report",
+        }
+    }
+}
+
+/// The name of the per-directory ignore files honored while walking.
+const IGNORE_FILE: &'static str = ".imgdupignore";
+
+/// A line of an ignore file, in `.gitignore` syntax.
+struct IgnoreRule {
+    glob: Glob,
+    /// A `!` rule re-includes what an earlier rule ignored.
+    negated: bool,
+    /// Rules ending in `/` only match directories.
+    dir_only: bool,
+}
+
+impl IgnoreRule {
+    fn parse(line: &str) -> Option<IgnoreRule> {
+        let line = line.trim_right();
+
+        if line.is_empty() || line.starts_with("#") { return None; }
+
+        let (negated, pattern) = if line.starts_with("!") {
+            (true, line.slice_from(1))
+        } else {
+            (false, line)
+        };
+
+        // `\#` and `\!` match names starting with those characters
+        let pattern = if pattern.starts_with("\\") { pattern.slice_from(1) } else { pattern };
+
+        if pattern.is_empty() { return None; }
+
+        Some(IgnoreRule {
+            glob: Glob::new(pattern),
+            negated: negated,
+            dir_only: pattern.ends_with("/"),
+        })
+    }
+}
+
+/// The rules of an ignore file, which apply to everything under its directory.
+struct IgnoreFile {
+    dir: Path,
+    rules: Vec<IgnoreRule>,
+}
+
+impl IgnoreFile {
+    fn load(dir: &Path) -> Option<IgnoreFile> {
+        let file = match File::open(&dir.join(IGNORE_FILE)) {
+            Ok(file) => file,
+            Err(_) => return None,
+        };
+
+        let rules = BufferedReader::new(file).lines()
+            .filter_map(|line| line.ok())
+            .filter_map(|line| IgnoreRule::parse(&*line))
+            .collect();
+
+        Some(IgnoreFile {
+            dir: dir.clone(),
+            rules: rules,
+        })
+    }
+}
+
+/// If the ignore files in effect ignore `path`. Later rules override earlier ones,
+/// and files in deeper directories override those above them.
+fn is_ignored(ignore_files: &[IgnoreFile], path: &Path, is_dir: bool) -> bool {
+    let mut ignored = false;
+
+    for ignore_file in ignore_files.iter() {
+        let rel_path = match path.path_relative_from(&ignore_file.dir) {
+            Some(rel_path) => rel_path,
+            None => continue,
+        };
+
+        for rule in ignore_file.rules.iter() {
+            if rule.dir_only && !is_dir { continue; }
+
+            if rule.glob.matches(&rel_path) {
+                ignored = !rule.negated;
+            }
+        }
+    }
+
+    ignored
+}
+
+/// Decides which paths are walked, by the `--include` and `--exclude` patterns.
+pub struct PathFilter {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+}
+
+impl PathFilter {
+    pub fn new(settings: &ProgramSettings) -> PathFilter {
+        PathFilter {
+            include: settings.include.iter().map(|pattern| Glob::new(&**pattern)).collect(),
+            exclude: settings.exclude.iter().map(|pattern| Glob::new(&**pattern)).collect(),
+        }
+    }
+
+    /// Excluded directories are not descended into.
+    fn is_excluded(&self, rel_path: &Path) -> bool {
+        self.exclude.iter().any(|glob| glob.matches(rel_path))
+    }
+
+    /// Include patterns only apply to files, so they don't stop the walk.
+    fn is_included(&self, rel_path: &Path) -> bool {
+        self.include.is_empty() || self.include.iter().any(|glob| glob.matches(rel_path))
+    }
+}
+
+/// The images to process: those listed by `--files-from` if given, otherwise those found 
+/// in the search directories.
+pub fn find_images(settings: &ProgramSettings) -> Result<Discovered, String> {
+    if let Some(ref list) = settings.files_from {
+        let paths = try!(read_path_list(list, settings.null_separated, settings.dir())
+            .map_err(|err| match *list {
+                PathList::Stdin => format!("Could not read list of files from standard input: {}", err),
+                PathList::File(ref path) => 
+                    format!("Could not read list of files {}: {}", path.display(), err),
+            }));
+
+        let mut found = FoundFiles::new();
+        let paths = paths.into_iter().filter(|path| found.is_new(path)).collect();
+
+        return Ok(Discovered {
+            paths: paths,
+            warnings: Vec::new(),
+            linked: found.linked,
+        });
+    }
+
+    let (tx, rx) = channel();
+    let mut discovered = try!(search(settings, tx, &AtomicBool::new(false)));
+
+    discovered.paths = rx.iter().collect();
+    // `--limit` should always pick the same images
+    discovered.paths.sort();
+
+    Ok(discovered)
+}
+
+/// Search the search directories in the background, sending on each image as soon as it's
+/// found so it can be processed while the search goes on.
+///
+/// The warnings and linked files are sent once the search is finished, without any paths,
+/// or the error that stopped it. Setting `stop` ends the search early.
+pub fn stream_images(settings: &ProgramSettings, stop: Arc<AtomicBool>) 
+        -> (Receiver<Path>, Receiver<Result<Discovered, String>>) {
+    let (path_tx, path_rx) = channel();
+    let (done_tx, done_rx) = channel();
+
+    let settings = settings.clone();
+
+    Thread::spawn(move || {
+        let _ = done_tx.send_opt(search(&settings, path_tx, &*stop));
+    }).detach();
+
+    (path_rx, done_rx)
+}
+
+/// Walk every search directory, sending each image to `tx` as it's found.
+fn search(settings: &ProgramSettings, tx: Sender<Path>, stop: &AtomicBool) 
+        -> Result<Discovered, String> {
+    let exts: Vec<&str> = settings.exts.iter().map(|string| string.as_slice()).collect();
+    let filter = PathFilter::new(settings);
+
+    let links = try!(LinkRecord::load(&settings.links_file())
+        .map_err(|err| format!("Could not read {}: {}", settings.links_file().display(), err)));
+
+    let mut found = FoundFiles::new();
+    let mut warnings = Vec::new();
+
+    for dir in settings.search_dirs().into_iter() {
+        let mut walker = Walker {
+            root: dir,
+            settings: settings,
+            exts: &*exts,
+            filter: &filter,
+            links: &links,
+            ignore_files: Vec::new(),
+            visited: HashSet::new(),
+            found: &mut found,
+            warnings: &mut warnings,
+            tx: &tx,
+            stop: stop,
+            stopped: false,
+            error: None,
+        };
+
+        walker.walk(dir, 0);
+
+        if let Some(err) = walker.error { return Err(err); }
+
+        if walker.stopped { break; }
+    }
+
+    warnings.sort_by(|left, right| left.path().cmp(right.path()));
+
+    let mut last: Option<Path> = None;
+
+    warnings.retain(|warning| {
+        let is_dup = last.as_ref() == Some(warning.path());
+        last = Some(warning.path().clone());
+        !is_dup
+    });
+
+    Ok(Discovered {
+        paths: Vec::new(),
+        warnings: warnings,
+        linked: found.linked,
+    })
+}
+
+/// Read the paths in `list`, one per line or separated by NUL bytes.
+///
+/// Every path is processed as given, so missing files are reported as processing errors.
+/// Relative paths are relative to `dir`.
+fn read_path_list(list: &PathList, null_separated: bool, dir: &Path) -> IoResult<Vec<Path>> {
+    let contents = try!(match *list {
+        PathList::Stdin => stdio::stdin_raw().read_to_end(),
+        PathList::File(ref path) => File::open(path).and_then(|mut file| file.read_to_end()),
+    });
+
+    let sep = if null_separated { b'\0' } else { b'\n' };
+
+    let mut paths: Vec<Path> = contents.split(|&byte| byte == sep)
+        // Lists made on Windows
+        .map(|entry| if !null_separated && entry.ends_with(b"\r") { 
+            entry.slice_to(entry.len() - 1) 
+        } else { 
+            entry 
+        })
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| dir.join(entry))
+        .collect();
+
+    // Like the directory search, so `--limit` always picks the same images
+    paths.sort();
+    paths.dedup();
+
+    Ok(paths)
+}
+
+/// The walk through one search directory.
+struct Walker<'a> {
+    root: &'a Path,
+    settings: &'a ProgramSettings,
+    exts: &'a [&'a str],
+    filter: &'a PathFilter,
+    links: &'a LinkRecord,
+    /// The ignore files of the directory being walked and those above it.
+    ignore_files: Vec<IgnoreFile>,
+    /// The device and inode of every directory walked, so links can't lead us in circles.
+    visited: HashSet<(u64, u64)>,
+    found: &'a mut FoundFiles,
+    warnings: &'a mut Vec<Warning>,
+    tx: &'a Sender<Path>,
+    /// Set by the user to end the search.
+    stop: &'a AtomicBool,
+    /// Set once nothing is receiving the images found anymore, or the search failed.
+    stopped: bool,
+    /// Why the search failed, if it did.
+    error: Option<String>,
+}
+
+impl<'a> Walker<'a> {
+    /// Send on the images under `dir` that pass the filter and aren't ignored, 
+    /// without descending into excluded directories.
+    ///
+    /// `depth` is how many levels of subdirectories `dir` is below the search directory.
+    fn walk(&mut self, dir: &Path, depth: uint) {
+        let mut entries = match fs::readdir(dir) {
+            Ok(entries) => entries,
+            // Unreadable subdirectories are skipped, but the search directory itself must be readable
+            Err(err) => {
+                if dir == self.root { 
+                    self.error = Some(format!("Could not read directory {}: {}", dir.display(), err));
+                    self.stopped = true;
+                }
+
+                return;
+            },
+        };
+
+        // Without inodes, e.g. on Windows, links can't be told apart from real directories
+        if let Some(id) = file_id(dir) {
+            if !self.visited.insert(id) { return; }
+        }
+
+        // So images are found, and the first of several paths to a file picked, the same way every time
+        entries.sort();
+
+        let ignore_file = IgnoreFile::load(dir);
+        let has_ignore_file = ignore_file.is_some();
+
+        self.ignore_files.extend(ignore_file.into_iter());
+
+        for entry in entries.into_iter() {
+            if self.stop.load(Relaxed) { self.stopped = true; }
+            if self.stopped { break; }
+
+            let rel_path = entry.path_relative_from(self.root).unwrap_or(entry.clone());
+
+            let lstat = match fs::lstat(&entry) {
+                Ok(lstat) => lstat,
+                Err(_) => continue,
+            };
+
+            let is_link = lstat.kind == FileType::Symlink;
+            // Whether the link's target is a directory, if it's a link
+            let is_dir = if is_link { entry.is_dir() } else { lstat.kind == FileType::Directory };
+
+            if self.filter.is_excluded(&rel_path) || is_ignored(&*self.ignore_files, &entry, is_dir) { 
+                continue; 
+            }
+
+            if is_link && !self.follow_link(&entry, &rel_path, is_dir) { continue; }
+
+            if is_dir {
+                if self.settings.recurse && self.settings.max_depth.map_or(true, |max| depth < max) { 
+                    self.walk(&entry, depth + 1); 
+                }
+            } else if self.filter.is_included(&rel_path) && self.within_limits(&entry, lstat, is_link) 
+                    && self.is_image(&entry) && self.found.is_new(&entry) {
+                if self.tx.send_opt(entry).is_err() { self.stopped = true; }
+            }
+        }
+
+        // This directory's rules don't apply to its siblings
+        if has_ignore_file { self.ignore_files.pop(); }
+    }
+
+    /// If the file's size and modification time are within the limits given.
+    fn within_limits(&self, file: &Path, lstat: FileStat, is_link: bool) -> bool {
+        let settings = self.settings;
+
+        let stat = if is_link {
+            match fs::stat(file) {
+                Ok(stat) => stat,
+                // Let the decoder report the error
+                Err(_) => return true,
+            }
+        } else {
+            lstat
+        };
+
+        settings.min_size.map_or(true, |min| stat.size >= min)
+            && settings.max_size.map_or(true, |max| stat.size <= max)
+            && settings.newer_than.map_or(true, |time| stat.modified >= time)
+            && settings.older_than.map_or(true, |time| stat.modified < time)
+    }
+
+    /// Check the file's extension, or its content with `--sniff`, and its dimensions.
+    fn is_image(&mut self, file: &Path) -> bool {
+        let is_image = if self.settings.sniff {
+            check_content(file, self.exts, &mut *self.warnings)
+        } else {
+            check_ext(file, self.exts)
+        };
+
+        is_image && check_dimensions(file, self.settings)
+    }
+
+    /// If the target of `link` looks like an image, without warning about its extension.
+    fn links_to_image(&self, link: &Path) -> bool {
+        if self.settings.sniff {
+            sniff(link).map_or(check_ext(link, self.exts), |format| format.is_wanted(self.exts))
+        } else {
+            check_ext(link, self.exts)
+        }
+    }
+
+    /// If the walk should go on to the target of `link`, recording it if it's to be reported.
+    fn follow_link(&mut self, link: &Path, rel_path: &Path, is_dir: bool) -> bool {
+        let target = match fs::readlink(link) {
+            Ok(target) => target,
+            Err(_) => return false,
+        };
+
+        // The links we put in place of duplicates aren't duplicates themselves
+        if self.links.is_ours(link, &target) { return false; }
+
+        match self.settings.symlinks {
+            SymlinkPolicy::Skip => false,
+            SymlinkPolicy::Follow => true,
+            SymlinkPolicy::Report => {
+                if !is_dir && self.filter.is_included(rel_path) && self.links_to_image(link) {
+                    self.warnings.push(Warning::Symlink(link.clone(), target));
+                }
+
+                false
+            },
+        }
+    }
+}
+
+fn check_ext(file: &Path, exts: &[&str]) -> bool {   
+    match file.extension_str() {
+        Some(ext) => any_ext_matches(exts, ext),
+        None => false
+    }
+}
+
+/// Decide by the file's content, warning if its extension says otherwise.
+fn check_content(file: &Path, exts: &[&str], warnings: &mut Vec<Warning>) -> bool {
+    match sniff(file) {
+        Some(format) => {
+            if !format.is_wanted(exts) { return false; }
+
+            if !format.has_ext_of(file) {
+                warnings.push(Warning::ExtensionMismatch(file.clone(), format));
+            }
+
+            true
+        },
+        // Let the decoder report what's wrong with files that claim to be images
+        None => check_ext(file, exts),
+    }
+}
+
+/// Check the image's width and height against the limits given, without decoding it.
+fn check_dimensions(file: &Path, settings: &ProgramSettings) -> bool {
+    if settings.min_dimensions.is_none() && settings.max_dimensions.is_none() { return true; }
+
+    let (width, height) = match read_dimensions(file) {
+        Some(dims) => dims,
+        // Let the decoder report the error
+        None => return true,
+    };
+
+    settings.min_dimensions.map_or(true, |(min_width, min_height)| 
+        width >= min_width && height >= min_height
+    ) && settings.max_dimensions.map_or(true, |(max_width, max_height)| 
+        width <= max_width && height <= max_height
+    )
+}
+
+fn any_ext_matches(exts: &[&str], ext: &str) -> bool {
+    exts.iter().any(|&a| a.eq_ignore_ascii_case(ext))
+}
+
+#[cfg(test)]
+mod test {
+    use super::dimensions_of;
+
+    use std::io::MemReader;
+
+    const PNG_SIG: &'static [u8] = b"\x89PNG\r\n\x1a\n";
+
+    fn dims(parts: &[&[u8]]) -> Option<(u32, u32)> {
+        let mut bytes = Vec::new();
+        for part in parts.iter() { bytes.push_all(*part); }
+
+        dimensions_of(&mut MemReader::new(bytes))
+    }
+
+    #[test]
+    fn png() {
+        let ihdr = [0, 0, 0, 13, b'I', b'H', b'D', b'R', 0, 0, 2, 128, 0, 0, 1, 224, 8, 2, 0, 0, 0];
+        assert_eq!(dims(&[PNG_SIG, ihdr.as_slice()]), Some((640, 480)));
+    }
+
+    #[test]
+    fn png_truncated() {
+        let ihdr = [0, 0, 0, 13, b'I', b'H', b'D', b'R', 0, 0, 2];
+        assert_eq!(dims(&[PNG_SIG, ihdr.as_slice()]), None);
+    }
+
+    const JPEG_APP0: &'static [u8] = &[
+        0xFF, 0xD8, 0xFF, 0xE0, 0, 16, b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0,
+    ];
+
+    #[test]
+    fn jpeg() {
+        // The frame header's marker is padded by an extra 0xFF
+        let sof = [
+            0xFF, 0xFF, 0xC0, 0, 17, 8, 1, 224, 2, 128, 3, 1, 0x22, 0, 2, 0x11, 1, 3, 0x11, 1,
+        ];
+        assert_eq!(dims(&[JPEG_APP0, sof.as_slice()]), Some((640, 480)));
+    }
+
+    #[test]
+    fn jpeg_progressive() {
+        let sof = [0xFF, 0xC2, 0, 17, 8, 0, 16, 0, 32, 3, 1, 0x22, 0, 2, 0x11, 1, 3, 0x11, 1];
+        assert_eq!(dims(&[JPEG_APP0, sof.as_slice()]), Some((32, 16)));
+    }
+
+    #[test]
+    fn jpeg_huffman_table_is_not_a_frame() {
+        let dht = [0xFF, 0xC4, 0, 4, 0, 0];
+        let sof = [0xFF, 0xC0, 0, 17, 8, 0, 10, 0, 20, 3, 1, 0x22, 0, 2, 0x11, 1, 3, 0x11, 1];
+        assert_eq!(dims(&[JPEG_APP0, dht.as_slice(), sof.as_slice()]), Some((20, 10)));
+    }
+
+    #[test]
+    fn jpeg_scan_before_frame() {
+        assert_eq!(dims(&[JPEG_APP0, [0xFF, 0xDA, 0, 2].as_slice()]), None);
+    }
+
+    #[test]
+    fn jpeg_truncated() {
+        // The APP0 segment claims more bytes than there are
+        assert_eq!(dims(&[JPEG_APP0.slice_to(12)]), None);
+        assert_eq!(dims(&[JPEG_APP0, [0xFF, 0xC0, 0, 17, 8, 1].as_slice()]), None);
+    }
+
+    #[test]
+    fn jpeg_garbage_between_segments() {
+        assert_eq!(dims(&[JPEG_APP0, [0x12, 0x34, 0x56, 0x78].as_slice()]), None);
+    }
+
+    #[test]
+    fn gif() {
+        let gif = b"GIF89a".as_slice();
+        assert_eq!(dims(&[gif, [128, 2, 224, 1, 0, 0].as_slice()]), Some((640, 480)));
+        assert_eq!(dims(&[b"GIF87a".as_slice(), [1, 0, 1, 0, 0, 0].as_slice()]), Some((1, 1)));
+    }
+
+    #[test]
+    fn gif_truncated() {
+        assert_eq!(dims(&[b"GIF89a".as_slice(), [128, 2, 224].as_slice()]), None);
+    }
+
+    const RIFF: &'static [u8] = &[b'R', b'I', b'F', b'F', 0, 0, 0, 0, b'W', b'E', b'B', b'P'];
+
+    #[test]
+    fn webp_lossy() {
+        let vp8 = [b'V', b'P', b'8', b' ', 0, 0, 0, 0, 0, 0, 0, 0x9D, 0x01, 0x2A, 128, 2, 224, 1];
+        assert_eq!(dims(&[RIFF, vp8.as_slice()]), Some((640, 480)));
+    }
+
+    #[test]
+    fn webp_lossless() {
+        // 640 - 1 in the low 14 bits, then 480 - 1
+        let bits: u32 = 639 | (479 << 14);
+        let vp8l = [
+            b'V', b'P', b'8', b'L', 0, 0, 0, 0, 0x2F,
+            bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8,
+        ];
+        assert_eq!(dims(&[RIFF, vp8l.as_slice()]), Some((640, 480)));
+    }
+
+    #[test]
+    fn webp_extended() {
+        let vp8x = [b'V', b'P', b'8', b'X', 10, 0, 0, 0, 0, 0, 0, 0, 127, 2, 0, 223, 1, 0];
+        assert_eq!(dims(&[RIFF, vp8x.as_slice()]), Some((640, 480)));
+    }
+
+    #[test]
+    fn webp_unknown_chunk() {
+        assert_eq!(dims(&[RIFF, b"ALPH".as_slice(), [0, 0, 0, 0, 0, 0, 0, 0].as_slice()]), None);
+    }
+
+    #[test]
+    fn webp_truncated() {
+        assert_eq!(dims(&[RIFF, [b'V', b'P', b'8', b'X', 10, 0, 0, 0, 0, 0].as_slice()]), None);
+    }
+
+    #[test]
+    fn short_header() {
+        assert_eq!(dims(&[PNG_SIG.slice_to(4)]), None);
+    }
+
+    #[test]
+    fn garbage() {
+        assert_eq!(dims(&[b"not an image at all".as_slice()]), None);
+        assert_eq!(dims(&[b"RIFF\0\0\0\0WAVEfmt ".as_slice()]), None);
+    }
+}
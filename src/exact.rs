@@ -0,0 +1,139 @@
+use img::Image;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use std::collections::HashMap;
+use std::io::fs::{mod, File};
+use std::io::{IoResult, EndOfFile};
+
+/// A file to decode and hash, along with any byte-identical copies of it.
+pub struct Job {
+    pub path: Path,
+    /// Hex SHA-256 of the file contents, if it was computed.
+    pub digest: Option<String>,
+    pub copies: Vec<Path>,
+}
+
+impl Job {
+    pub fn single(path: Path) -> Job {
+        Job {
+            path: path,
+            digest: None,
+            copies: Vec::new(),
+        }
+    }
+}
+
+/// Group byte-identical files so only one of each group has to be decoded.
+///
+/// Files are first grouped by size, and only files sharing a size with another are read in full.
+pub fn group_exact(paths: Vec<Path>) -> Vec<Job> {
+    let mut jobs = Vec::new();
+    let mut by_size: HashMap<u64, Vec<Path>> = HashMap::new();
+
+    for path in paths.into_iter() {
+        let size = match fs::stat(&path) {
+            Ok(stat) => stat.size,
+            // Let the decoder report the error
+            Err(_) => { jobs.push(Job::single(path)); continue; },
+        };
+
+        if let Some(group) = by_size.get_mut(&size) {
+            group.push(path);
+            continue;
+        }
+
+        by_size.insert(size, vec![path]);
+    }
+
+    for (_, group) in by_size.into_iter() {
+        if group.len() == 1 {
+            jobs.extend(group.into_iter().map(Job::single));
+        } else {
+            group_by_digest(group, &mut jobs);
+        }
+    }
+
+    jobs
+}
+
+fn group_by_digest(group: Vec<Path>, jobs: &mut Vec<Job>) {
+    let mut by_digest: HashMap<String, Job> = HashMap::new();
+
+    for path in group.into_iter() {
+        let digest = match file_digest(&path) {
+            Ok(digest) => digest,
+            Err(_) => { jobs.push(Job::single(path)); continue; },
+        };
+
+        if let Some(job) = by_digest.get_mut(&digest) {
+            job.copies.push(path);
+            continue;
+        }
+
+        by_digest.insert(digest.clone(), Job {
+            path: path,
+            digest: Some(digest),
+            copies: Vec::new(),
+        });
+    }
+
+    jobs.extend(by_digest.into_iter().map(|(_, mut job)| {
+        // The group arrived in order, but make sure the representative is always the same file
+        job.copies.sort();
+        job
+    }));
+}
+
+/// Compute the digests that `group_exact` would have for images hashed in separate runs,
+/// e.g. when resuming a scan, so exact copies on either side of the interruption are still found.
+pub fn fill_digests(images: &mut [Image]) {
+    let mut by_size: HashMap<u64, Vec<uint>> = HashMap::new();
+
+    for (idx, image) in images.iter().enumerate() {
+        if let Some(group) = by_size.get_mut(&image.size) {
+            group.push(idx);
+            continue;
+        }
+
+        by_size.insert(image.size, vec![idx]);
+    }
+
+    for (_, group) in by_size.into_iter() {
+        if group.len() == 1 { continue; }
+
+        for &idx in group.iter() {
+            if images[idx].digest.is_some() { continue; }
+
+            images[idx].digest = file_digest(&images[idx].path).ok();
+        }
+    }
+}
+
+/// Digest of decoded RGBA pixels. The dimensions are included so that 
+/// e.g. a 2x8 and a 4x4 image with the same pixels don't collide.
+pub fn pixel_digest(width: u32, height: u32, rgba: &[u8]) -> String {
+    let mut sha = Sha256::new();
+
+    sha.input_str(&*format!("{}x{}:", width, height));
+    sha.input(rgba);
+
+    sha.result_str()
+}
+
+pub fn file_digest(path: &Path) -> IoResult<String> {
+    let mut file = try!(File::open(path));
+    let mut sha = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+
+    loop {
+        match file.read(&mut buf) {
+            Ok(read) => sha.input(buf.slice_to(read)),
+            Err(ref err) if err.kind == EndOfFile => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(sha.result_str())
+}
@@ -0,0 +1,218 @@
+use img::Image;
+
+use std::collections::HashSet;
+use std::io::{BufferedReader, IoResult, IoErrorKind, Append, Write};
+use std::io::fs::{File, PathExtensions};
+
+const HEADER: &'static str = "# img_dup ignore list: pairs of images that are not duplicates";
+
+/// A pair of images a reviewer has marked as not duplicates, whatever their hashes say.
+///
+/// The pair is kept in sorted order so it can be looked up either way around.
+#[deriving(PartialEq, Eq, Clone)]
+pub enum Entry {
+    /// Matched by path.
+    Paths(Path, Path),
+    /// Matched by pixel digest, which survives moves and renames. The paths the images had 
+    /// when they were marked are kept, in the same order, so the entry can be pruned once 
+    /// they're both gone. Entries written before the paths were kept don't have them.
+    Pixels(String, String, Option<(Path, Path)>),
+}
+
+impl Entry {
+    pub fn paths(left: Path, right: Path) -> Entry {
+        let (left, right) = sorted(left, right);
+        Entry::Paths(left, right)
+    }
+
+    pub fn pixels(left: String, right: String, paths: Option<(Path, Path)>) -> Entry {
+        if left <= right { 
+            Entry::Pixels(left, right, paths) 
+        } else { 
+            Entry::Pixels(right, left, paths.map(|(left, right)| (right, left))) 
+        }
+    }
+
+    /// Prefer the content of the images to their paths, if we have it.
+    pub fn for_images(left: &Image, right: &Image) -> Entry {
+        match (&left.pixel_digest, &right.pixel_digest) {
+            (&Some(ref left_digest), &Some(ref right_digest)) => Entry::pixels(
+                left_digest.clone(), right_digest.clone(), 
+                Some((left.path.clone(), right.path.clone()))
+            ),
+            _ => Entry::paths(left.path.clone(), right.path.clone()),
+        }
+    }
+
+    /// If the entry refers to a file that no longer exists. Since pixel entries follow the 
+    /// images wherever they're moved, they're only stale once neither is where it was.
+    pub fn is_stale(&self) -> bool {
+        match *self {
+            Entry::Paths(ref left, ref right) => !left.exists() || !right.exists(),
+            Entry::Pixels(_, _, Some((ref left, ref right))) => !left.exists() && !right.exists(),
+            // We can't tell without scanning
+            Entry::Pixels(_, _, None) => false,
+        }
+    }
+
+    fn parse(line: &str) -> Option<Entry> {
+        let fields: Vec<&str> = line.split('\t').collect();
+
+        match (fields[0], fields.len()) {
+            ("path", 3) => Some(Entry::paths(unescape_path(fields[1]), unescape_path(fields[2]))),
+            ("pixels", 3) => Some(Entry::pixels(fields[1].to_string(), fields[2].to_string(), None)),
+            ("pixels", 5) => Some(Entry::pixels(
+                fields[1].to_string(), fields[2].to_string(), 
+                Some((unescape_path(fields[3]), unescape_path(fields[4])))
+            )),
+            _ => None,
+        }
+    }
+
+    fn write_self(&self, out: &mut Writer) -> IoResult<()> {
+        match *self {
+            Entry::Paths(ref left, ref right) =>
+                writeln!(out, "path\t{}\t{}", escape_path(left), escape_path(right)),
+            Entry::Pixels(ref left, ref right, Some((ref left_path, ref right_path))) =>
+                writeln!(out, "pixels\t{}\t{}\t{}\t{}", left, right, 
+                         escape_path(left_path), escape_path(right_path)),
+            Entry::Pixels(ref left, ref right, None) =>
+                writeln!(out, "pixels\t{}\t{}", left, right),
+        }
+    }
+}
+
+fn sorted<T: PartialOrd>(left: T, right: T) -> (T, T) {
+    if left <= right { (left, right) } else { (right, left) }
+}
+
+/// Paths may contain the tabs that separate the fields, or even newlines. `%` is escaped 
+/// rather than `\`, which is common in Windows paths written before paths were escaped.
+fn escape_path(path: &Path) -> String {
+    path.display().to_string().replace("%", "%25").replace("\t", "%09").replace("\n", "%0A")
+}
+
+fn unescape_path(field: &str) -> Path {
+    Path::new(field.replace("%0A", "\n").replace("%09", "\t").replace("%25", "%"))
+}
+
+pub struct IgnoreList {
+    paths: HashSet<(Path, Path)>,
+    pixels: HashSet<(String, String)>,
+}
+
+impl IgnoreList {
+    pub fn new() -> IgnoreList {
+        IgnoreList { 
+            paths: HashSet::new(),
+            pixels: HashSet::new(),
+        }
+    }
+
+    /// Load the list, which is empty if the file doesn't exist yet.
+    pub fn load(path: &Path) -> IoResult<IgnoreList> {
+        let mut list = IgnoreList::new();
+
+        for entry in try!(read_entries(path)).into_iter() {
+            match entry {
+                Entry::Paths(left, right) => { list.paths.insert((left, right)); },
+                Entry::Pixels(left, right, _) => { list.pixels.insert((left, right)); },
+            }
+        }
+
+        Ok(list)
+    }
+
+    pub fn is_ignored(&self, left: &Image, right: &Image) -> bool {
+        if self.paths.is_empty() && self.pixels.is_empty() { return false; }
+
+        if self.paths.contains(&sorted(left.path.clone(), right.path.clone())) {
+            return true;
+        }
+
+        match (&left.pixel_digest, &right.pixel_digest) {
+            (&Some(ref left), &Some(ref right)) =>
+                self.pixels.contains(&sorted(left.clone(), right.clone())),
+            _ => false,
+        }
+    }
+}
+
+/// Read the entries in the order they were added.
+pub fn read_entries(path: &Path) -> IoResult<Vec<Entry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind == IoErrorKind::FileNotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut entries = Vec::new();
+
+    for line in BufferedReader::new(file).lines() {
+        let line = try!(line);
+        let line = line.trim_right_matches('\n');
+
+        if line.is_empty() || line.starts_with("#") { continue; }
+
+        // Skip lines we don't understand rather than losing the rest of the list
+        if let Some(entry) = Entry::parse(line) {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Mark two images as not duplicates.
+pub fn append(path: &Path, left: &Image, right: &Image) -> IoResult<()> {
+    let is_new = !path.exists();
+    let mut file = try!(File::open_mode(path, Append, Write));
+
+    if is_new {
+        try!(file.write_line(HEADER));
+    }
+
+    Entry::for_images(left, right).write_self(&mut file)
+}
+
+/// Remove entries for files that no longer exist, returning the number removed.
+pub fn prune(path: &Path) -> IoResult<uint> {
+    let entries = try!(read_entries(path));
+    let count = entries.len();
+
+    let kept: Vec<Entry> = entries.into_iter().filter(|entry| !entry.is_stale()).collect();
+    let removed = count - kept.len();
+
+    if removed > 0 {
+        let mut file = try!(File::create(path));
+        try!(file.write_line(HEADER));
+
+        for entry in kept.iter() {
+            try!(entry.write_self(&mut file));
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Write the list in a human-readable form.
+pub fn write_list(out: &mut Writer, path: &Path) -> IoResult<()> {
+    let entries = try!(read_entries(path));
+
+    try!(writeln!(out, "Ignore list: {} ({} entries)\n", path.display(), entries.len()));
+
+    for entry in entries.iter() {
+        let stale = if entry.is_stale() { " [missing]" } else { "" };
+
+        match *entry {
+            Entry::Paths(ref left, ref right) =>
+                try!(writeln!(out, "{} / {}{}", left.display(), right.display(), stale)),
+            Entry::Pixels(_, _, Some((ref left, ref right))) =>
+                try!(writeln!(out, "{} / {} (by content){}", left.display(), right.display(), stale)),
+            Entry::Pixels(ref left, ref right, None) =>
+                try!(writeln!(out, "pixels {} / {}", left, right)),
+        }
+    }
+
+    Ok(())
+}
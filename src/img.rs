@@ -1,3 +1,5 @@
+use config::{DimensionGuards, Roots, Threshold};
+
 use img_hash::ImageHash;
 
 use serialize::json::{Json, ToJson};
@@ -13,6 +15,14 @@ pub struct Image {
     pub hash: ImageHash,
     pub width: u32,
     pub height: u32,
+    /// Hex SHA-256 of the file contents, only computed for files that might be exact copies.
+    pub digest: Option<String>,
+    /// Hex SHA-256 of the decoded RGBA pixels and dimensions.
+    pub pixel_digest: Option<String>,
+    /// File size in bytes when the image was hashed.
+    pub size: u64,
+    /// File modification time (ms since the epoch) when the image was hashed.
+    pub modified: u64,
 }
 
 impl Image {
@@ -23,21 +33,55 @@ impl Image {
             hash: hash,
             width: width,
             height: height,
+            digest: None,
+            pixel_digest: None,
+            size: 0,
+            modified: 0,
         } 
     }
 
-    fn relative_path(&self, relative_to: &Path) -> Path {
-        self.path.path_relative_from(relative_to).unwrap_or(self.path.clone())
+    /// If both files' contents are known to be byte-identical.
+    pub fn is_exact_copy(&self, other: &Image) -> bool {
+        self.digest.is_some() && self.digest == other.digest
+    }
+
+    pub fn pixels(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
+    pub fn aspect_ratio(&self) -> f32 {
+        self.width as f32 / self.height as f32
+    }
+
+    /// The linear size of `other` relative to this image.
+    pub fn scale_of(&self, other: &Image) -> f32 {
+        let area = (self.width as f32) * (self.height as f32);
+        let other_area = (other.width as f32) * (other.height as f32);
+
+        (other_area / area).sqrt()
+    }
+
+    /// If both files are known to decode to the same pixels.
+    pub fn is_pixel_identical(&self, other: &Image) -> bool {
+        self.pixel_digest.is_some() && self.pixel_digest == other.pixel_digest
     }
 
-    pub fn to_treemap(&self, relative_to: &Path) -> BTreeMap<String, Json> {
+    pub fn to_treemap(&self, roots: &Roots) -> BTreeMap<String, Json> {
         let mut json = BTreeMap::new();
 
-        json_insert!(json, "path", self.relative_path(relative_to).display().to_string());
+        roots.insert_json(&mut json, "path", "root", &self.path);
         json_insert!(json, "hash", self.hash.to_base64());
         json_insert!(json, "width", &self.width);
         json_insert!(json, "height", &self.height);
 
+        if let Some(ref digest) = self.digest {
+            json_insert!(json, "sha256", digest);
+        }
+
+        if let Some(ref pixel_digest) = self.pixel_digest {
+            json_insert!(json, "pixels_sha256", pixel_digest);
+        }
+
         json
     }
 }
@@ -56,14 +100,22 @@ impl UniqueImage {
         }
     }
     
-    pub fn is_similar(&self, img: &Image, thresh: f32) -> bool {
-        self.img.hash.dist_ratio(&img.hash) < thresh
+    pub fn is_similar(&self, img: &Image, thresh: &Threshold, guards: &DimensionGuards) -> Similarity {
+        if !thresh.is_within(&self.img.hash, &img.hash) {
+            return Similarity::Dissimilar;
+        }
+
+        match guards.check(&self.img, img) {
+            Some(failure) => Similarity::Rejected(failure),
+            None => Similarity::Similar,
+        }
     }
  
     pub fn add_similar(&mut self, img: Image) {
         let dist_ratio = self.img.hash.dist_ratio(&img.hash);
+        let kind = MatchKind::between(&self.img, &img);
 
-        self.similars.push(SimilarImage::from_image(img, dist_ratio));
+        self.similars.push(SimilarImage::from_image(img, dist_ratio, kind));
     }
 
     pub fn similars(&self) -> Vec<SimilarImage> {
@@ -72,26 +124,36 @@ impl UniqueImage {
         temp    
     }
 
-    pub fn write_self(&self, out: &mut Writer, relative_to: &Path) -> IoResult<()> {
-        try!(writeln!(out, "Original: ({}x{}) {} ", 
+    pub fn write_self(&self, out: &mut Writer, roots: &Roots) -> IoResult<()> {
+        self.write_labeled(out, roots, "Original", "Similars")
+    }
+
+    pub fn write_labeled(&self, out: &mut Writer, roots: &Roots, 
+                         img_label: &str, similars_label: &str) -> IoResult<()> {
+        try!(writeln!(out, "{}: ({}x{}) {} ", 
+                    img_label,
                     self.img.width, self.img.height,
-                    self.img.relative_path(relative_to).display()
+                    roots.display(&self.img.path)
                 ));
         
-        try!(out.write_line("Similars [% different]:"));
+        try!(writeln!(out, "{} [% different]:", similars_label));
     
-        for similar in self.similars().iter() {
-            try!(similar.write_self(out, relative_to));
+        let similars = self.similars();
+
+        for (idx, similar) in similars.iter().enumerate() {
+            try!(similar.write_self(out, roots, closer_copy(&*similars, idx)));
         }
 
         out.write_char('\n')
     }
 
-    pub fn to_json(&self, relative_to: &Path) -> Json {
-        let mut json = self.img.to_treemap(relative_to);
+    pub fn to_json(&self, roots: &Roots) -> Json {
+        let mut json = self.img.to_treemap(roots);
+
+        let similars = self.similars();
 
-        let similars_json: Vec<Json> = self.similars.iter()
-            .map( |similar| similar.to_json(relative_to) )
+        let similars_json: Vec<Json> = similars.iter().enumerate()
+            .map( |(idx, similar)| similar.to_json(roots, closer_copy(&*similars, idx)) )
             .collect();
 
         json_insert!(json, "similars", similars_json);
@@ -104,6 +166,7 @@ impl UniqueImage {
         for similar in self.similars.iter_mut() {
             let dist_ratio = self.img.hash.dist_ratio(&similar.img.hash);
             similar.dist_ratio = dist_ratio;
+            similar.kind = MatchKind::between(&self.img, &similar.img);
         }
         
         self.similars.sort()
@@ -115,34 +178,165 @@ pub struct SimilarImage {
    pub img: Image, 
    // Distance from the containing UniqueImage
    pub dist_ratio: f32,
+   // How this image matched the containing UniqueImage
+   pub kind: MatchKind,
 }
 
 impl SimilarImage {
 
-    fn from_image(img: Image, dist_ratio: f32) -> SimilarImage {
+    fn from_image(img: Image, dist_ratio: f32, kind: MatchKind) -> SimilarImage {
         SimilarImage {
             img: img,
             dist_ratio: dist_ratio,
+            kind: kind,
         }
     }
 
-    fn write_self(&self, out: &mut Writer, relative_to: &Path) -> IoResult<()> {
-        writeln!(out, "[{0:.2}%] ({1}x{2}) {3}",
-            self.dist_ratio * 100f32,
+    fn write_self(&self, out: &mut Writer, roots: &Roots, 
+                  copy: Option<(&Path, MatchKind)>) -> IoResult<()> {
+        match self.kind {
+            MatchKind::Perceptual => try!(write!(out, "[{0:.2}%] ", self.dist_ratio * 100f32)),
+            kind => try!(write!(out, "[{}] ", kind.name())),
+        }
+
+        try!(write!(out, "({0}x{1}) {2}",
             self.img.width, self.img.height,
-            self.img.relative_path(relative_to).display()
+            roots.display(&self.img.path)
+        ));
+
+        match copy {
+            Some((path, kind)) => writeln!(out, " [{} copy of {}]", kind.name(), roots.display(path)),
+            None => out.write_char('\n'),
+        }
+    }
+
+    fn to_json(&self, roots: &Roots, copy: Option<(&Path, MatchKind)>) -> Json {
+        let mut json = self.img.to_treemap(roots);
+
+        json_insert!(json, "diff", self.dist_ratio);
+        json_insert!(json, "match", self.kind.name());
+
+        if let Some((path, kind)) = copy {
+            roots.insert_json(&mut json, "copy_of", "copy_of_root", path);
+            json_insert!(json, "copy_match", kind.name());
+        }
+
+        Json::Object(json)
+    }
+}
+
+/// The earlier of `similars` that the one at `idx` is the closest copy of, if it's closer to it 
+/// than to the original, e.g. two byte-identical files that only perceptually match the original.
+fn closer_copy(similars: &[SimilarImage], idx: uint) -> Option<(&Path, MatchKind)> {
+    let ref similar = similars[idx];
+    let mut closest = None;
+
+    for other in similars.slice_to(idx).iter() {
+        let kind = MatchKind::between(&other.img, &similar.img);
+
+        if kind < closest.map_or(similar.kind, |(_, closest_kind)| closest_kind) {
+            closest = Some((&other.img.path, kind));
+        }
+    }
+
+    closest
+}
+
+pub enum Similarity {
+    Similar,
+    Dissimilar,
+    /// The hashes matched but the dimensions didn't pass the guards.
+    Rejected(GuardFailure),
+}
+
+#[deriving(Copy, Clone, PartialEq)]
+pub enum GuardFailure {
+    /// The aspect ratios of the original and the other image.
+    AspectRatio(f32, f32),
+    /// The linear size of the other image relative to the original.
+    Scale(f32),
+}
+
+impl GuardFailure {
+    pub fn describe(&self) -> String {
+        match *self {
+            GuardFailure::AspectRatio(orig, other) => 
+                format!("aspect ratio {:.3} vs. {:.3}", other, orig),
+            GuardFailure::Scale(scale) => format!("scale {:.3}x", scale),
+        }
+    }
+}
+
+/// A hash match that was rejected by the dimension guards.
+pub struct Rejection {
+    pub original: Path,
+    pub img: Path,
+    pub dist_ratio: f32,
+    pub failure: GuardFailure,
+}
+
+impl Rejection {
+    pub fn new(original: &Image, img: &Image, failure: GuardFailure) -> Rejection {
+        Rejection {
+            original: original.path.clone(),
+            img: img.path.clone(),
+            dist_ratio: original.hash.dist_ratio(&img.hash),
+            failure: failure,
+        }
+    }
+
+    pub fn write_self(&self, out: &mut Writer, roots: &Roots) -> IoResult<()> {
+        writeln!(out, "[{0:.2}%] {1} vs. {2}: {3}",
+            self.dist_ratio * 100f32,
+            roots.display(&self.img),
+            roots.display(&self.original),
+            self.failure.describe()
         )
     }
 
-    fn to_json(&self, relative_to: &Path) -> Json {
-        let mut json = self.img.to_treemap(relative_to);
+    pub fn to_json(&self, roots: &Roots) -> Json {
+        let mut json = BTreeMap::new();
 
+        roots.insert_json(&mut json, "path", "root", &self.img);
+        roots.insert_json(&mut json, "original", "original_root", &self.original);
         json_insert!(json, "diff", self.dist_ratio);
+        json_insert!(json, "reason", self.failure.describe());
 
         Json::Object(json)
     }
 }
 
+/// How sure we are that two images are the same, from most to least certain.
+#[deriving(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Show)]
+pub enum MatchKind {
+    /// The files are byte-identical.
+    Exact,
+    /// The files decode to the same pixels, e.g. after recompression or metadata edits.
+    PixelIdentical,
+    /// The perceptual hashes are within the threshold.
+    Perceptual,
+}
+
+impl MatchKind {
+    pub fn between(left: &Image, right: &Image) -> MatchKind {
+        if left.is_exact_copy(right) {
+            MatchKind::Exact
+        } else if left.is_pixel_identical(right) {
+            MatchKind::PixelIdentical
+        } else {
+            MatchKind::Perceptual
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MatchKind::Exact => "exact",
+            MatchKind::PixelIdentical => "pixel-identical",
+            MatchKind::Perceptual => "perceptual",
+        }
+    }
+}
+
 impl Ord for SimilarImage {
     fn cmp(&self, other: &SimilarImage) -> Ordering {
         self.partial_cmp(other).unwrap_or(Equal)   
@@ -150,8 +344,12 @@ impl Ord for SimilarImage {
 }
 
 impl PartialOrd for SimilarImage {
+    /// Closest first, ties broken by path so the order is stable.
     fn partial_cmp(&self, other: &SimilarImage) -> Option<Ordering> {
-        self.dist_ratio.partial_cmp(&other.dist_ratio)                    
+        match self.dist_ratio.partial_cmp(&other.dist_ratio) {
+            Some(Equal) => Some(self.img.path.cmp(&other.img.path)),
+            ord => ord,
+        }
     }    
 }
 
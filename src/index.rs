@@ -0,0 +1,379 @@
+use config::{HashSettings, ProgramSettings};
+use img::Image;
+
+use img_hash::ImageHash;
+
+use serialize::json::{mod, Json, ToJson};
+
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{BufferedReader, BufferedWriter, IoError, IoResult, InvalidInput};
+use std::io::fs::File;
+use std::os;
+
+const VERSION: u64 = 1;
+
+/// The first bytes of a portable index. See INDEX.md for the format.
+const MAGIC: &'static [u8] = b"IMGDUPIX";
+const PORTABLE_VERSION: u16 = 1;
+/// The root index of an image that wasn't under any root, whose path is stored as-is.
+const NO_ROOT: u32 = 0xFFFF_FFFF;
+
+/// The counts in a portable index come from the file, so don't reserve more than this up front 
+/// on their say-so; a truncated or corrupt file then fails when it runs out, not when allocating.
+const MAX_RESERVE: uint = 4096;
+
+/// No string we write comes anywhere near this long.
+const MAX_STRING: uint = 1 << 16;
+
+/// The hashes from a run, saved so they can be matched again without re-decoding the images.
+pub struct HashIndex {
+    pub settings: HashSettings,
+    /// The roots of a portable index. Empty for hash files saved with `--save-hashes`.
+    pub roots: Vec<Root>,
+    pub images: Vec<Image>,
+}
+
+/// A directory the paths of a portable index are stored relative to.
+#[deriving(Clone)]
+pub struct Root {
+    /// Where the images are on this machine.
+    pub path: Path,
+    /// What the root is called in the index file.
+    pub label: String,
+}
+
+impl Root {
+    /// A root read back from an index, whose images are given paths under its label.
+    fn imported(label: String) -> Root {
+        Root {
+            path: Path::new(label.clone()),
+            label: label,
+        }
+    }
+}
+
+impl HashIndex {
+
+    pub fn load(path: &Path) -> Result<HashIndex, String> {
+        let contents = try!(
+            File::open(path).and_then(|mut file| file.read_to_string())
+                .map_err(|err| format!("Could not read hash file {}: {}", path.display(), err))
+        );
+
+        let json = try!(
+            json::from_str(&*contents)
+                .map_err(|err| format!("Hash file {} is not valid JSON: {}", path.display(), err))
+        );
+
+        from_json(&json).ok_or_else(|| format!("Hash file {} is invalid or from an unsupported version",
+                                               path.display()))
+    }
+
+    /// Load a portable index written by `export`.
+    pub fn import(path: &Path) -> Result<HashIndex, String> {
+        let file = try!(
+            File::open(path)
+                .map_err(|err| format!("Could not read index {}: {}", path.display(), err))
+        );
+
+        read_portable(&mut BufferedReader::new(file))
+            .map_err(|err| format!("Index {} is invalid or from an unsupported version: {}", 
+                                   path.display(), err))
+    }
+
+    /// Add the roots and images of `other`, which must use the same hash settings.
+    /// Images of `other` replace any with the same path.
+    pub fn merge(&mut self, other: HashIndex) -> Result<(), String> {
+        if self.settings != other.settings {
+            return Err(format!(
+                "Cannot merge indexes with different hash settings (hash size {} and {}, fast {} and {})",
+                self.settings.hash_size, other.settings.hash_size, self.settings.fast, other.settings.fast
+            ));
+        }
+
+        for root in other.roots.into_iter() {
+            if !self.roots.iter().any(|own| own.label == root.label) {
+                self.roots.push(root);
+            }
+        }
+
+        let replaced: HashSet<Path> = other.images.iter().map(|image| image.path.clone()).collect();
+
+        self.images.retain(|image| !replaced.contains(&image.path));
+        self.images.extend(other.images.into_iter());
+
+        Ok(())
+    }
+
+    /// The roots to use when exporting this index again.
+    pub fn export_roots(&self, settings: &ProgramSettings) -> Vec<Root> {
+        if self.roots.is_empty() { export_roots(settings) } else { self.roots.clone() }
+    }
+}
+
+/// Load and merge the portable indexes at `paths`.
+pub fn import_all(paths: &[Path]) -> Result<HashIndex, String> {
+    let mut merged: Option<HashIndex> = None;
+
+    for path in paths.iter() {
+        let index = try!(HashIndex::import(path));
+
+        merged = match merged {
+            Some(mut merged) => { try!(merged.merge(index)); Some(merged) },
+            None => Some(index),
+        };
+    }
+
+    merged.ok_or_else(|| "No indexes to import".to_string())
+}
+
+/// The search directories, labeled with `--index-label` if given.
+pub fn export_roots(settings: &ProgramSettings) -> Vec<Root> {
+    let cwd = os::getcwd().unwrap();
+
+    settings.search_dirs().into_iter()
+        .map(|dir| {
+            let absolute = cwd.join(dir).display().to_string();
+
+            Root {
+                path: dir.clone(),
+                label: match settings.index_label {
+                    Some(ref label) => format!("{}:{}", label, absolute),
+                    None => absolute,
+                },
+            }
+        })
+        .collect()
+}
+
+/// Write `images` as a portable index, with their paths relative to `roots`.
+pub fn export(path: &Path, settings: &HashSettings, roots: &[Root], images: &[Image]) -> IoResult<()> {
+    let mut out = BufferedWriter::new(try!(File::create(path)));
+
+    try!(out.write(MAGIC));
+    try!(out.write_be_u16(PORTABLE_VERSION));
+    try!(out.write_be_u32(settings.hash_size));
+    try!(out.write_u8(settings.fast as u8));
+
+    try!(out.write_be_u32(roots.len() as u32));
+
+    for root in roots.iter() {
+        try!(write_string(&mut out, &*root.label));
+    }
+
+    try!(out.write_be_u64(images.len() as u64));
+
+    for image in images.iter() {
+        // The innermost root, in case they're nested
+        let root = roots.iter().enumerate()
+            .filter(|&(_, root)| root.path.is_ancestor_of(&image.path))
+            .max_by(|&(_, root)| root.path.components().count());
+
+        let (root_idx, rel_path) = match root {
+            Some((idx, root)) => (idx as u32, image.path.path_relative_from(&root.path).unwrap()),
+            None => (NO_ROOT, image.path.clone()),
+        };
+
+        try!(out.write_be_u32(root_idx));
+        try!(write_string(&mut out, &*rel_path.display().to_string()));
+        try!(out.write_be_u32(image.width));
+        try!(out.write_be_u32(image.height));
+        try!(out.write_be_u64(image.size));
+        try!(out.write_be_u64(image.modified));
+        try!(write_string(&mut out, &*image.hash.to_base64()));
+        try!(write_opt_string(&mut out, &image.digest));
+        try!(write_opt_string(&mut out, &image.pixel_digest));
+    }
+
+    out.flush()
+}
+
+fn write_string(out: &mut Writer, string: &str) -> IoResult<()> {
+    try!(out.write_be_u32(string.len() as u32));
+    out.write_str(string)
+}
+
+fn write_opt_string(out: &mut Writer, string: &Option<String>) -> IoResult<()> {
+    match *string {
+        Some(ref string) => {
+            try!(out.write_u8(1));
+            write_string(out, &**string)
+        },
+        None => out.write_u8(0),
+    }
+}
+
+fn read_portable(input: &mut Reader) -> Result<HashIndex, String> {
+    let magic = try!(input.read_exact(MAGIC.len()).map_err(|err| err.to_string()));
+
+    if &*magic != MAGIC { return Err("not an img_dup index".to_string()); }
+
+    let version = try!(input.read_be_u16().map_err(|err| err.to_string()));
+
+    if version != PORTABLE_VERSION { return Err(format!("unknown version {}", version)); }
+
+    read_portable_v1(input).map_err(|err| err.to_string())
+}
+
+fn read_portable_v1(input: &mut Reader) -> IoResult<HashIndex> {
+    let settings = HashSettings {
+        hash_size: try!(input.read_be_u32()),
+        fast: try!(input.read_u8()) != 0,
+    };
+
+    let root_count = try!(input.read_be_u32()) as uint;
+    let mut roots = Vec::with_capacity(cmp::min(root_count, MAX_RESERVE));
+
+    for _ in range(0, root_count) {
+        roots.push(Root::imported(try!(read_string(input))));
+    }
+
+    let image_count = try!(input.read_be_u64()) as uint;
+    let mut images = Vec::with_capacity(cmp::min(image_count, MAX_RESERVE));
+
+    for _ in range(0, image_count) {
+        let root_idx = try!(input.read_be_u32());
+        let rel_path = Path::new(try!(read_string(input)));
+
+        let path = match roots.get(root_idx as uint) {
+            Some(root) => root.path.join(rel_path),
+            None if root_idx == NO_ROOT => rel_path,
+            None => return Err(invalid("root out of range")),
+        };
+
+        let width = try!(input.read_be_u32());
+        let height = try!(input.read_be_u32());
+        let size = try!(input.read_be_u64());
+        let modified = try!(input.read_be_u64());
+
+        let hash = try!(
+            ImageHash::from_base64(&*try!(read_string(input)))
+                .map_err(|_| invalid("bad hash"))
+        );
+
+        let mut image = Image::new(path, hash, width, height);
+        image.size = size;
+        image.modified = modified;
+        image.digest = try!(read_opt_string(input));
+        image.pixel_digest = try!(read_opt_string(input));
+
+        images.push(image);
+    }
+
+    Ok(HashIndex {
+        settings: settings,
+        roots: roots,
+        images: images,
+    })
+}
+
+fn read_string(input: &mut Reader) -> IoResult<String> {
+    let len = try!(input.read_be_u32()) as uint;
+
+    if len > MAX_STRING { return Err(invalid("string too long")); }
+
+    let bytes = try!(input.read_exact(len));
+
+    String::from_utf8(bytes).map_err(|_| invalid("string is not UTF-8"))
+}
+
+fn read_opt_string(input: &mut Reader) -> IoResult<Option<String>> {
+    match try!(input.read_u8()) {
+        0 => Ok(None),
+        _ => read_string(input).map(Some),
+    }
+}
+
+fn invalid(desc: &'static str) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: desc,
+        detail: None,
+    }
+}
+
+/// Save the hashes without taking ownership of the images.
+pub fn save_hashes(path: &Path, settings: &HashSettings, images: &[Image]) -> IoResult<()> {
+    let mut json = BTreeMap::new();
+
+    let images_json: Vec<Json> = images.iter().map(image_to_json).collect();
+
+    json_insert!(json, "version", VERSION);
+    json_insert!(json, "hash_size", settings.hash_size);
+    json_insert!(json, "fast", settings.fast);
+    json_insert!(json, "images", images_json);
+
+    let mut file = try!(File::create(path));
+    try!(file.write_str(&*Json::Object(json).to_string()));
+    file.write_line("")
+}
+
+fn image_to_json(image: &Image) -> Json {
+    let mut json = BTreeMap::new();
+
+    json_insert!(json, "path", image.path.display().to_string());
+    json_insert!(json, "hash", image.hash.to_base64());
+    json_insert!(json, "width", image.width);
+    json_insert!(json, "height", image.height);
+    json_insert!(json, "sha256", image.digest);
+    json_insert!(json, "pixels_sha256", image.pixel_digest);
+    json_insert!(json, "size", image.size);
+    json_insert!(json, "modified", image.modified);
+
+    Json::Object(json)
+}
+
+fn from_json(json: &Json) -> Option<HashIndex> {
+    if u64_field(json, "version") != Some(VERSION) { return None; }
+
+    let settings = match (u64_field(json, "hash_size"), json.find("fast").and_then(|fast| fast.as_boolean())) {
+        (Some(hash_size), Some(fast)) => HashSettings { hash_size: hash_size as u32, fast: fast },
+        _ => return None,
+    };
+
+    let images_json = match json.find("images").and_then(|images| images.as_array()) {
+        Some(images) => images,
+        None => return None,
+    };
+
+    let mut images = Vec::with_capacity(images_json.len());
+
+    for image_json in images_json.iter() {
+        match image_from_json(image_json) {
+            Some(image) => images.push(image),
+            None => return None,
+        }
+    }
+
+    Some(HashIndex {
+        settings: settings,
+        roots: Vec::new(),
+        images: images,
+    })
+}
+
+fn image_from_json(json: &Json) -> Option<Image> {
+    let hash = str_field(json, "hash").and_then(|hash| ImageHash::from_base64(&*hash).ok());
+
+    match (str_field(json, "path"), hash, u64_field(json, "width"), u64_field(json, "height")) {
+        (Some(path), Some(hash), Some(width), Some(height)) => {
+            let mut image = Image::new(Path::new(path), hash, width as u32, height as u32);
+            image.digest = str_field(json, "sha256");
+            image.pixel_digest = str_field(json, "pixels_sha256");
+            // Older files don't have these, so their images all count as modified
+            image.size = u64_field(json, "size").unwrap_or(0);
+            image.modified = u64_field(json, "modified").unwrap_or(0);
+            Some(image)
+        },
+        _ => None,
+    }
+}
+
+fn str_field(json: &Json, key: &str) -> Option<String> {
+    json.find(key).and_then(|val| val.as_string()).map(|val| val.to_string())
+}
+
+fn u64_field(json: &Json, key: &str) -> Option<u64> {
+    json.find(key).and_then(|val| val.as_u64())
+}
@@ -0,0 +1,243 @@
+use config::{HashSettings, ProgramSettings};
+use img::Image;
+use processing::{ProcessingError, TimedImageResult};
+
+use img_hash::ImageHash;
+
+use std::collections::HashSet;
+use std::io::{BufferedReader, BufferedWriter, IoError, IoResult, Append, Write};
+use std::io::fs::{mod, File, PathExtensions};
+
+/// Flush the journal to disk after this many results.
+const CHECKPOINT_EVERY: uint = 64;
+
+/// A record of the images processed so far, so an interrupted scan can be resumed.
+///
+/// Results are appended as they arrive and flushed every `CHECKPOINT_EVERY` results.
+/// The file is removed once the scan finishes.
+pub struct Journal {
+    path: Option<Path>,
+    writer: Option<BufferedWriter<File>>,
+    unflushed: uint,
+}
+
+/// Results recovered from a journal.
+pub struct Resumed {
+    pub images: Vec<Image>,
+    pub errors: Vec<ProcessingError>,
+}
+
+impl Resumed {
+    fn new() -> Resumed {
+        Resumed {
+            images: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.images.len() + self.errors.len()
+    }
+
+    /// The paths of all the results, which don't need processing again.
+    pub fn paths(&self) -> HashSet<Path> {
+        self.images.iter().map(|image| image.path.clone())
+            .chain(self.errors.iter().map(|error| error.path().clone()))
+            .collect()
+    }
+}
+
+/// Open the journal given in `settings`, if any. When resuming, the results already in the
+/// journal are returned along with the paths that still need processing.
+pub fn start(settings: &ProgramSettings, paths: Vec<Path>)
+    -> Result<(Journal, Resumed, Vec<Path>), String> {
+    let (journal, resumed) = try!(open(settings));
+
+    if resumed.len() == 0 { return Ok((journal, resumed, paths)); }
+
+    let done = resumed.paths();
+    let remaining = paths.into_iter().filter(|path| !done.contains(path)).collect();
+
+    Ok((journal, resumed, remaining))
+}
+
+/// Open the journal given in `settings`, if any, along with the results already in it 
+/// when resuming.
+pub fn open(settings: &ProgramSettings) -> Result<(Journal, Resumed), String> {
+    let journal_path = match settings.journal {
+        Some(ref path) => path.clone(),
+        None => return Ok((Journal::disabled(), Resumed::new())),
+    };
+
+    let hash_settings = settings.hash_settings();
+
+    if settings.resume && journal_path.exists() {
+        let (resumed, complete_len) = try!(read_journal(&journal_path, &hash_settings));
+
+        let mut file = try!(File::open_mode(&journal_path, Append, Write)
+                        .map_err(|err| open_err(&journal_path, err)));
+
+        // Drop the last line if we were killed in the middle of writing it, so a later 
+        // resume doesn't read it as a complete one
+        try!(file.truncate(complete_len as i64).map_err(|err| open_err(&journal_path, err)));
+
+        Ok((Journal::new(journal_path, file), resumed))
+    } else {
+        let mut file = try!(File::create(&journal_path).map_err(|err| open_err(&journal_path, err)));
+        try!(write_header(&mut file, &hash_settings).map_err(|err| open_err(&journal_path, err)));
+
+        Ok((Journal::new(journal_path, file), Resumed::new()))
+    }
+}
+
+impl Journal {
+    fn new(path: Path, file: File) -> Journal {
+        Journal {
+            path: Some(path),
+            writer: Some(BufferedWriter::new(file)),
+            unflushed: 0,
+        }
+    }
+
+    pub fn disabled() -> Journal {
+        Journal {
+            path: None,
+            writer: None,
+            unflushed: 0,
+        }
+    }
+
+    pub fn record(&mut self, result: &TimedImageResult) -> IoResult<()> {
+        let writer = match self.writer {
+            Some(ref mut writer) => writer,
+            None => return Ok(()),
+        };
+
+        match *result {
+            Ok((ref image, _, _)) => try!(writeln!(writer, "ok\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                image.size, image.modified, image.width, image.height,
+                image.hash.to_base64(),
+                opt_field(&image.digest),
+                opt_field(&image.pixel_digest),
+                image.path.display()
+            )),
+            Err(ref error) => try!(writeln!(writer, "err\t{}\t{}",
+                // Keep the message on one line
+                error.err_msg().replace("\t", " ").replace("\n", " "),
+                error.path().display()
+            )),
+        }
+
+        self.unflushed += 1;
+
+        if self.unflushed >= CHECKPOINT_EVERY {
+            self.unflushed = 0;
+            try!(writer.flush());
+        }
+
+        Ok(())
+    }
+
+    /// The scan was cut short, so write out everything recorded for it to be resumed.
+    pub fn keep(mut self) -> IoResult<()> {
+        match self.writer {
+            Some(ref mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// The scan completed, so the journal is no longer needed.
+    pub fn finish(mut self) -> IoResult<()> {
+        // Close the file first
+        self.writer = None;
+
+        match self.path {
+            Some(ref path) => fs::unlink(path),
+            None => Ok(()),
+        }
+    }
+}
+
+fn open_err(path: &Path, err: IoError) -> String {
+    format!("Could not open journal {}: {}", path.display(), err)
+}
+
+fn opt_field(field: &Option<String>) -> &str {
+    field.as_ref().map_or("-", |field| &**field)
+}
+
+fn write_header(file: &mut File, settings: &HashSettings) -> IoResult<()> {
+    writeln!(file, "img_dup journal\t1\t{}\t{}", settings.hash_size, settings.fast)
+}
+
+/// Read the results in the journal, and the length of the complete lines they were read from.
+fn read_journal(path: &Path, settings: &HashSettings) -> Result<(Resumed, u64), String> {
+    let file = try!(File::open(path)
+        .map_err(|err| format!("Could not read journal {}: {}", path.display(), err)));
+
+    let mut lines = BufferedReader::new(file).lines();
+
+    let header = lines.next().and_then(|line| line.ok()).unwrap_or(String::new());
+    let expected = format!("img_dup journal\t1\t{}\t{}\n", settings.hash_size, settings.fast);
+
+    if header != expected {
+        return Err(format!("Journal {} is from a different version or hash settings",
+                           path.display()));
+    }
+
+    let mut resumed = Resumed::new();
+    let mut complete_len = header.len() as u64;
+
+    for line in lines {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        // The last line may be incomplete if we were killed mid-write; just redo that image
+        if !line.ends_with("\n") { break; }
+
+        complete_len += line.len() as u64;
+        parse_line(line.trim_right_matches('\n'), &mut resumed);
+    }
+
+    Ok((resumed, complete_len))
+}
+
+fn parse_line(line: &str, resumed: &mut Resumed) {
+    if line.starts_with("err\t") {
+        let fields: Vec<&str> = line.splitn(2, '\t').collect();
+
+        if fields.len() == 3 {
+            resumed.errors.push(
+                ProcessingError::Resumed(Path::new(fields[2]), fields[1].to_string())
+            );
+        }
+
+        return;
+    }
+
+    if !line.starts_with("ok\t") { return; }
+
+    let fields: Vec<&str> = line.splitn(8, '\t').collect();
+
+    if fields.len() != 9 { return; }
+
+    let hash = ImageHash::from_base64(fields[5]).ok();
+
+    match (fields[1].parse(), fields[2].parse(), fields[3].parse(), fields[4].parse(), hash) {
+        (Some(size), Some(modified), Some(width), Some(height), Some(hash)) => {
+            let mut image = Image::new(Path::new(fields[8]), hash, width, height);
+            image.size = size;
+            image.modified = modified;
+            image.digest = parse_opt(fields[6]);
+            image.pixel_digest = parse_opt(fields[7]);
+            resumed.images.push(image);
+        },
+        _ => (),
+    }
+}
+
+fn parse_opt(field: &str) -> Option<String> {
+    if field == "-" { None } else { Some(field.to_string()) }
+}
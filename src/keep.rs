@@ -0,0 +1,141 @@
+use img::{Image, UniqueImage};
+
+use std::ascii::AsciiExt;
+use std::io::FileStat;
+use std::io::fs;
+use std::u64;
+
+/// A rule for deciding which image in a group is the original, i.e. the one to keep.
+#[deriving(PartialEq, Clone, Show)]
+pub enum KeepPolicy {
+    /// The most pixels.
+    Resolution,
+    /// The largest file on disk.
+    LargestFile,
+    /// The earliest modification time.
+    Oldest,
+    /// The latest modification time.
+    Newest,
+    /// Anything under the given directory.
+    Prefer(Path),
+    /// Lossless formats (e.g. PNG) over lossy ones (e.g. JPEG).
+    Lossless,
+}
+
+const LOSSLESS_EXTS: [&'static str; 5] = ["png", "bmp", "gif", "tif", "tiff"];
+
+impl KeepPolicy {
+    pub fn parse(policy: &str) -> Option<KeepPolicy> {
+        if policy.starts_with("prefer:") {
+            return Some(KeepPolicy::Prefer(Path::new(policy.slice_from("prefer:".len()))));
+        }
+
+        match policy {
+            "resolution" => Some(KeepPolicy::Resolution),
+            "largest" => Some(KeepPolicy::LargestFile),
+            "oldest" => Some(KeepPolicy::Oldest),
+            "newest" => Some(KeepPolicy::Newest),
+            "lossless" => Some(KeepPolicy::Lossless),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match *self {
+            KeepPolicy::Resolution => "resolution".to_string(),
+            KeepPolicy::LargestFile => "largest".to_string(),
+            KeepPolicy::Oldest => "oldest".to_string(),
+            KeepPolicy::Newest => "newest".to_string(),
+            KeepPolicy::Prefer(ref dir) => format!("prefer:{}", dir.display()),
+            KeepPolicy::Lossless => "lossless".to_string(),
+        }
+    }
+
+    /// `Less` if `left` should be kept over `right`.
+    fn compare(&self, left: &Member, right: &Member) -> Ordering {
+        match *self {
+            KeepPolicy::Resolution => right.img.pixels().cmp(&left.img.pixels()),
+            KeepPolicy::LargestFile => right.size().cmp(&left.size()),
+            KeepPolicy::Oldest => 
+                left.modified().unwrap_or(u64::MAX).cmp(&right.modified().unwrap_or(u64::MAX)),
+            KeepPolicy::Newest => 
+                right.modified().unwrap_or(0).cmp(&left.modified().unwrap_or(0)),
+            KeepPolicy::Prefer(ref dir) =>
+                dir.is_ancestor_of(&right.img.path).cmp(&dir.is_ancestor_of(&left.img.path)),
+            KeepPolicy::Lossless => is_lossless(right.img).cmp(&is_lossless(left.img)),
+        }
+    }
+}
+
+/// An image in a group, with its file metadata if it could be read.
+struct Member<'a> {
+    img: &'a Image,
+    stat: Option<FileStat>,
+}
+
+impl<'a> Member<'a> {
+    fn new(img: &'a Image) -> Member<'a> {
+        Member {
+            img: img,
+            stat: fs::stat(&img.path).ok(),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.stat.as_ref().map_or(0, |stat| stat.size)
+    }
+
+    // Files we can't stat sort last for both `Oldest` and `Newest`
+    fn modified(&self) -> Option<u64> {
+        self.stat.as_ref().map(|stat| stat.modified)
+    }
+}
+
+fn is_lossless(img: &Image) -> bool {
+    img.path.extension_str().map_or(false, |ext|
+        LOSSLESS_EXTS.iter().any(|lossless| lossless.eq_ignore_ascii_case(ext))
+    )
+}
+
+/// Make the image preferred by `policies` the group's original.
+/// Ties are broken by each policy in turn, and if they all tie, the current original is kept.
+pub fn choose_original(unique: &mut UniqueImage, policies: &[KeepPolicy]) {
+    if policies.is_empty() || unique.similars.is_empty() { return; }
+
+    let best = {
+        let original = Member::new(&unique.img);
+        let similars: Vec<Member> = unique.similars.iter()
+            .map(|similar| Member::new(&similar.img))
+            .collect();
+
+        let mut best: Option<uint> = None;
+
+        for (idx, similar) in similars.iter().enumerate() {
+            let current = match best {
+                Some(best_idx) => &similars[best_idx],
+                None => &original,
+            };
+
+            if compare_all(policies, similar, current) == Less {
+                best = Some(idx);
+            }
+        }
+
+        best
+    };
+
+    if let Some(idx) = best {
+        unique.promote(idx);
+    }
+}
+
+fn compare_all(policies: &[KeepPolicy], left: &Member, right: &Member) -> Ordering {
+    for policy in policies.iter() {
+        match policy.compare(left, right) {
+            Equal => continue,
+            ord => return ord,
+        }
+    }
+
+    Equal
+}
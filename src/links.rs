@@ -0,0 +1,61 @@
+use std::collections::HashSet;
+use std::io::{BufferedReader, IoResult, IoErrorKind, Append, Write};
+use std::io::fs::{File, PathExtensions};
+
+const HEADER: &'static str = "# img_dup links: symbolic links created in place of duplicates";
+
+/// The symbolic links img_dup has put in place of duplicates, so later scans 
+/// don't report them as duplicates of their targets.
+pub struct LinkRecord {
+    /// Each link and the target it was created with.
+    links: HashSet<(Path, Path)>,
+}
+
+impl LinkRecord {
+    pub fn new() -> LinkRecord {
+        LinkRecord { links: HashSet::new() }
+    }
+
+    /// Load the record, which is empty if the file doesn't exist yet.
+    pub fn load(path: &Path) -> IoResult<LinkRecord> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind == IoErrorKind::FileNotFound => return Ok(LinkRecord::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut record = LinkRecord::new();
+
+        for line in BufferedReader::new(file).lines() {
+            let line = try!(line);
+            let line = line.trim_right_matches('\n');
+
+            if line.is_empty() || line.starts_with("#") { continue; }
+
+            let fields: Vec<&str> = line.split('\t').collect();
+
+            if fields.len() == 2 {
+                record.links.insert((Path::new(fields[0]), Path::new(fields[1])));
+            }
+        }
+
+        Ok(record)
+    }
+
+    /// If img_dup created `link`, and it still points where it did then.
+    pub fn is_ours(&self, link: &Path, target: &Path) -> bool {
+        self.links.contains(&(link.clone(), target.clone()))
+    }
+}
+
+/// Record that `link` was created, pointing to `target`.
+pub fn append(path: &Path, link: &Path, target: &Path) -> IoResult<()> {
+    let is_new = !path.exists();
+    let mut file = try!(File::open_mode(path, Append, Write));
+
+    if is_new {
+        try!(file.write_line(HEADER));
+    }
+
+    writeln!(&mut file, "{}\t{}", link.display(), target.display())
+}
@@ -1,6 +1,8 @@
 #![feature(macro_rules, globs, unsafe_destructor, phase)]
 
+extern crate crypto;
 extern crate getopts;
+extern crate glob;
 extern crate image;
 extern crate img_hash;
 extern crate libc;
@@ -8,9 +10,12 @@ extern crate serialize;
 extern crate time;
 
 use config::{parse_args, ProgramSettings};
-use output::{output_results, test_outfile};
+use index::HashIndex;
+use output::{output_results, test_outfile, test_writable};
 use processing::process;
 
+use std::io;
+use std::io::fs::PathExtensions;
 use std::io::util::NullWriter;
 
 use std::os;
@@ -21,11 +26,22 @@ macro_rules! json_insert(
     );
 );
 
+mod cache;
 mod config;
+mod discovery;
+mod exact;
 mod img;
+mod journal;
+mod ignore_list;
+mod index;
+mod keep;
+mod links;
 mod output;
 mod processing;
+mod query;
+mod rescan;
 mod par_queue;
+mod xattr;
 
 #[cfg(feature = "gui")]
 mod ui;
@@ -41,6 +57,12 @@ pub fn exit() {
     unsafe { libc::exit(0); }   
 }
 
+/// Print `msg` to stderr and exit with an error status, for errors in the input we were given.
+pub fn fail(msg: &str) -> ! {
+    let _ = writeln!(&mut io::stderr(), "img_dup: {}", msg);
+    unsafe { libc::exit(1); }
+}
+
 #[cfg(feature = "gui")]
 fn show_gui(settings: ProgramSettings) {   
 	ui::show_gui(settings);
@@ -54,13 +76,17 @@ fn show_gui(_: ProgramSettings) {
 fn run() {
     let args = os::args();
 
-    let settings = parse_args(args.as_slice());
+    let mut settings = parse_args(args.as_slice());
 
 	if settings.gui {
         show_gui(settings);
 		return;
 	}
 
+    if manage_ignore_list(&settings) {
+        return;
+    }
+
     // Silence standard messages if we're outputting JSON
     let mut out = get_output(&settings);    
 
@@ -72,10 +98,81 @@ fn run() {
         },
         None => (),        
     };
+
+    if let Some(ref hashes) = settings.save_hashes {
+        (writeln!(out, "Testing hash file ({})...", hashes.display())).unwrap();
+        test_writable(hashes).unwrap();
+    }
+
+    if let Some(ref export) = settings.export_index {
+        (writeln!(out, "Testing index file ({})...", export.display())).unwrap();
+        test_writable(export).unwrap();
+    }
+
+    let saved = load_saved(&settings, &mut *out);
+
+    if let Some(ref saved) = saved {
+        settings.use_hash_settings(saved.settings);
+    }
     
-    out.write_line("Searching for images...").unwrap();
+    let index = settings.update_index.clone().and_then(|index_path| {
+        if !index_path.exists() {
+            (writeln!(out, "Creating index at {}...", index_path.display())).unwrap();
+            return None;
+        }
+
+        (writeln!(out, "Loading index from {}...", index_path.display())).unwrap();
+        let index = HashIndex::load(&index_path).unwrap_or_else(|err| panic!("{}", err));
+        settings.use_hash_settings(index.settings);
+        Some(index)
+    });
+
+    let query = settings.query.as_ref().map(|query| {
+        (writeln!(out, "Hashing query...")).unwrap();
+        query::load_query(&**query, &settings).unwrap_or_else(|err| panic!("{}", err))
+    });
+
+    if let Some(saved) = saved {
+        (writeln!(out, "Hashes loaded: {}\n", saved.images.len())).unwrap();
+
+        if let Some(ref hashes_path) = settings.save_hashes {
+            index::save_hashes(hashes_path, &saved.settings, &*saved.images).unwrap();
+        }
+
+        if let Some(ref export_path) = settings.export_index {
+            index::export(export_path, &saved.settings, &*saved.export_roots(&settings), 
+                          &*saved.images).unwrap();
+        }
+
+        return match query {
+            Some(query) => output::output_query(&settings, 
+                &query::query_hashes(&settings, query, saved.images)),
+            None => output::output_results(&settings, 
+                &processing::recluster(&settings, saved.images)),
+        }.unwrap();
+    }
+
+    if settings.stream_discovery() && settings.update_index.is_none() && query.is_none() {
+        (writeln!(out, "Searching for and processing images in {} threads. Please wait...\n", 
+                 settings.threads)).unwrap();
+
+        let results = processing::process_streamed(&settings);
+
+        out.write_line("").unwrap();
+
+        return output::output_results(&settings, &results).unwrap();
+    }
+
+    out.write_line(if settings.files_from.is_some() { 
+        "Reading list of files..." 
+    } else { 
+        "Searching for images..." 
+    }).unwrap();
 
-    let mut image_paths = processing::find_images(&settings);
+    let discovered = discovery::find_images(&settings).unwrap_or_else(|err| fail(&*err));
+    let mut image_paths = discovered.paths;
+    let warnings = discovered.warnings;
+    let mut linked = discovered.linked;
 
     let image_count = image_paths.len();
 
@@ -84,18 +181,75 @@ fn run() {
     if settings.limit > 0 {
         (writeln!(out, "Limiting to: {}", settings.limit)).unwrap();
         image_paths.truncate(settings.limit);
+
+        // The paths are sorted, so what's left of them is those up to the last one kept
+        let last = image_paths.last().cloned();
+        linked.retain(|set| Some(&set.path) <= last.as_ref());
     }
 
     (writeln!(out, "Processing images in {} threads. Please wait...\n", 
              settings.threads)).unwrap();
 
-    let results = processing::process(&settings, image_paths);
+    if let Some(ref index_path) = settings.update_index {
+        let (mut results, changes) = rescan::rescan(&settings, index_path, index, image_paths);
+        results.warnings = warnings;
+        results.linked = linked;
+
+        out.write_line("").unwrap();
+        (writeln!(out, "Unchanged: {}", changes.unchanged)).unwrap();
+        (writeln!(out, "New or modified: {}", changes.hashed)).unwrap();
+        (writeln!(out, "Removed from index: {}\n", changes.removed)).unwrap();
+
+        return output::output_results(&settings, &results).unwrap();
+    }
+
+    if let Some(query) = query {
+        let results = query::query(&settings, query, image_paths);
+
+        out.write_line("").unwrap();
+
+        return output::output_query(&settings, &results).unwrap();
+    }
+
+    let mut results = processing::process(&settings, image_paths);
+    results.warnings = warnings;
+    results.linked = linked;
 
     out.write_line("").unwrap();
 
     output::output_results(&settings, &results).unwrap()   
 }
 
+/// Load the hashes given with `--load-hashes`, or merge the indexes given with `--import-index`.
+fn load_saved(settings: &ProgramSettings, out: &mut Writer) -> Option<HashIndex> {
+    if !settings.import_index.is_empty() {
+        (writeln!(out, "Importing {} indexes...", settings.import_index.len())).unwrap();
+        return Some(index::import_all(&*settings.import_index).unwrap_or_else(|err| panic!("{}", err)));
+    }
+
+    settings.load_hashes.as_ref().map(|hashes| {
+        (writeln!(out, "Loading hashes from {}...", hashes.display())).unwrap();
+        HashIndex::load(hashes).unwrap_or_else(|err| panic!("{}", err))
+    })
+}
+
+/// Handle `--list-ignored` and `--prune-ignored`, returning `true` if either was given.
+fn manage_ignore_list(settings: &ProgramSettings) -> bool {
+    let ref list = settings.ignore_list;
+    let mut out = std::io::stdio::stdout();
+
+    if settings.prune_ignored {
+        let removed = ignore_list::prune(list).unwrap();
+        (writeln!(out, "Removed {} entries for missing files from {}", removed, list.display())).unwrap();
+    }
+
+    if settings.list_ignored {
+        ignore_list::write_list(&mut out, list).unwrap();
+    }
+
+    settings.prune_ignored || settings.list_ignored
+}
+
 fn get_output(settings: &ProgramSettings) -> Box<Writer> {
     if settings.silent_stdout() {
         box NullWriter as Box<Writer> 
@@ -1,5 +1,6 @@
 use config::{ProgramSettings, JsonSettings};
 use processing::Results;
+use query::QueryResults;
 
 use serialize::Encodable;
 
@@ -10,7 +11,7 @@ use std::collections::BTreeMap;
 
 use std::io::fs::File;
 use std::io::stdio::{stdout, StdWriter};
-use std::io::{IoResult, LineBufferedWriter};
+use std::io::{Append, IoResult, LineBufferedWriter, Write};
 
 pub fn newline_before_after(out: &mut Writer, what: |&mut Writer| -> IoResult<()>) -> IoResult<()> {
     try!(out.write_line(""));
@@ -32,12 +33,21 @@ fn json_output(settings: &ProgramSettings, results: &Results, out: &mut Writer)
     let output = {
         let mut json = BTreeMap::new();
  
-        let dir = &settings.dir;
+        let ref roots = settings.roots();
 
         json_insert!(json, "settings", settings);
-        json_insert!(json, "info", results.info_json());
-        json_insert!(json, "images", results.uniques_json(dir, settings.dup_only));
-        json_insert!(json, "errors", results.errors_json(dir));
+        json_insert!(json, "info", results.info_json(settings.reference_mode()));
+        let images_key = if settings.reference_mode() { "candidates" } else { "images" };
+
+        if settings.cross_root {
+            json_insert!(json, "roots", results.roots_json(settings));
+        } else {
+            json_insert!(json, images_key, results.uniques_json(roots, settings.dup_only));
+        }
+        json_insert!(json, "rejected", results.rejections_json(roots));
+        json_insert!(json, "errors", results.errors_json(roots));
+        json_insert!(json, "warnings", results.warnings_json(roots));
+        json_insert!(json, "linked", results.linked_json(roots));
 
         Json::Object(json)
     };
@@ -47,6 +57,48 @@ fn json_output(settings: &ProgramSettings, results: &Results, out: &mut Writer)
     out.write_line("")
 }
 
+pub fn output_query(settings: &ProgramSettings, results: &QueryResults) -> IoResult<()> {
+    let ref mut out = open_output(settings);
+    let ref roots = settings.roots();
+
+    if settings.json.is_json() {
+        let mut json = BTreeMap::new();
+        let mut info = BTreeMap::new();
+
+        json_insert!(info, "start", results.start_time.ctime().to_string());
+        json_insert!(info, "end", results.end_time.ctime().to_string());
+        json_insert!(info, "searched", results.searched);
+        json_insert!(info, "errors", results.errors.len());
+
+        let errors: Vec<Json> = results.errors.iter()
+            .map(|error| error.to_json(roots))
+            .collect();
+
+        json_insert!(json, "settings", settings);
+        json_insert!(json, "info", Json::Object(info));
+        json_insert!(json, "query", results.nearest.to_json(roots));
+        json_insert!(json, "errors", errors);
+
+        try!(json_encode(&settings.json, Json::Object(json), out));
+        return out.write_line("");
+    }
+
+    try!(out.write_line("img-dup query results follow.\nStats:"));
+    try!(writeln!(out, "Start time: {}", results.start_time.ctime()));
+    try!(writeln!(out, "End time: {}", results.end_time.ctime()));
+    try!(writeln!(out, "Images searched: {}", results.searched));
+    try!(writeln!(out, "Errors: {}", results.errors.len()));
+    try!(newline_before_after(out, 
+        |outa| results.nearest.write_labeled(outa, roots, "Query", "Closest matches")));
+    try!(out.write_line("\nErrors:\n"));
+
+    for error in results.errors.iter() {
+        try!(newline_before_after(out, |outa| error.write_self(outa, roots)));
+    }
+
+    Ok(())
+}
+
 fn json_encode(json_config: &JsonSettings, json: Json, out: &mut Writer) -> IoResult<()> {
     match *json_config {
         JsonSettings::PrettyJson(indent) => { 
@@ -63,12 +115,36 @@ fn json_encode(json_config: &JsonSettings, json: Json, out: &mut Writer) -> IoRe
 }
 
 fn write_output(settings: &ProgramSettings, results: &Results, out: &mut Writer) -> IoResult<()> {
+    let ref roots = settings.roots();
+
     try!(out.write_line("img-dup results follow.\nStats:"));
-    try!(results.write_info(out));
-    try!(out.write_line("\nImages:\n"));
-    try!(results.write_uniques(out, &settings.dir, settings.dup_only));
+    try!(results.write_info(out, settings.reference_mode()));
+    if settings.cross_root {
+        try!(out.write_line("\nRoots:"));
+        try!(results.write_roots(out, settings));
+    } else {
+        try!(out.write_line(if settings.reference_mode() { "\nCandidates:\n" } else { "\nImages:\n" }));
+        try!(results.write_uniques(out, roots, settings.dup_only, settings.reference_mode()));
+    }
+    if !results.rejections.is_empty() {
+        try!(out.write_line("\nRejected by dimension guards [% different]:\n"));
+        try!(results.write_rejections(out, roots));
+    }
+
     try!(out.write_line("\nErrors:\n"));
-    results.write_errors(out, &settings.dir)    
+    try!(results.write_errors(out, roots));
+
+    if !results.warnings.is_empty() {
+        try!(out.write_line("\nWarnings:\n"));
+        try!(results.write_warnings(out, roots));
+    }
+
+    if !results.linked.is_empty() {
+        try!(out.write_line("\nSame file under several paths (processed once):\n"));
+        try!(results.write_linked(out, roots));
+    }
+
+    Ok(())
 }
 
 fn open_output(settings: &ProgramSettings) -> Either<File, LineBufferedWriter<StdWriter>> {
@@ -99,3 +175,9 @@ pub fn test_outfile(outfile: &Path) -> IoResult<()> {
     File::create(outfile).map(|_| ())
 }
 
+/// Test if the file is writable without truncating it, in case it's also read before 
+/// it's written, e.g. the same file given to `--load-hashes` and `--save-hashes`.
+pub fn test_writable(path: &Path) -> IoResult<()> {
+    File::open_mode(path, Append, Write).map(|_| ())
+}
+
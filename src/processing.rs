@@ -1,7 +1,15 @@
-use config::{ProgramSettings, HashSettings};
-use img::{Image, UniqueImage};
+use cache::{mod, HashCache};
+use config::{ProgramSettings, HashSettings, Role, Roots};
+use discovery::{mod, LinkedFiles, Warning};
+use exact::{mod, Job};
+use ignore_list::IgnoreList;
+use index;
+use journal::{mod, Journal, Resumed};
+use keep;
+use img::{Image, UniqueImage, Rejection, Similarity};
 use output::newline_before_after;
 use par_queue::ParQueue;
+use xattr;
 
 use image;
 use image::{DynamicImage, GenericImage, ImageError};
@@ -12,12 +20,13 @@ use serialize::json::{ToJson, Json};
 
 use time::{Tm, now, precise_time_ns};
 
-use std::ascii::AsciiExt;
 use std::boxed::BoxAny;
-use std::collections::BTreeMap;
-use std::io::IoResult;
-use std::io::fs::PathExtensions;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufferedReader, IoResult, SeekSet};
+use std::io::fs::{mod, File};
 use std::rt::unwind::try;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUint, Relaxed};
 use std::thread::Thread;
 
 pub struct Results {
@@ -25,7 +34,11 @@ pub struct Results {
     pub start_time: Tm,
     pub end_time: Tm,
     pub uniques: Vec<UniqueImage>,
+    pub rejections: Vec<Rejection>,
     pub errors: Vec<ProcessingError>,    
+    pub warnings: Vec<Warning>,
+    /// Files found under more than one path, which were only processed once.
+    pub linked: Vec<LinkedFiles>,
 }
 
 unsafe impl Send for Results {}
@@ -40,54 +53,117 @@ impl Results {
         self.end_time.ctime().to_string()
     }    
 
-    pub fn info_json(&self) -> Json {
+    pub fn info_json(&self, reference_mode: bool) -> Json {
         let mut info = BTreeMap::new();
         json_insert!(info, "start", self.start_time());
         json_insert!(info, "end", self.end_time());
         json_insert!(info, "found", self.total);
         json_insert!(info, "processed", self.uniques.len());
         json_insert!(info, "errors", self.errors.len());
+        json_insert!(info, "warnings", self.warnings.len());
+        json_insert!(info, "linked", self.linked.len());
+        json_insert!(info, "reclaimable_bytes", reclaimable(&*self.uniques, reference_mode));
 
         Json::Object(info)
     }
 
-    pub fn uniques_json(&self, relative_to: &Path, dup_only: bool) -> Json {
+    pub fn uniques_json(&self, roots: &Roots, dup_only: bool) -> Json {
         let uniques_json: Vec<Json> = self.uniques.iter()
         .filter_map( |unique| 
                 if dup_only && unique.similars.is_empty() {
                     None
                 } else {
-                    Some(unique.to_json(relative_to))
+                    Some(unique.to_json(roots))
                 }  
         ).collect();
 
         Json::Array(uniques_json)
     }
 
-    pub fn errors_json(&self, relative_to: &Path) -> Json {
+    pub fn roots_json(&self, settings: &ProgramSettings) -> Json {
+        let roots = settings.roots();
+
+        let roots_json: Vec<Json> = self.by_root(settings).into_iter()
+            .map(|summary| {
+                let mut json = BTreeMap::new();
+
+                let shared: Vec<Json> = summary.shared.iter()
+                    .map(|unique| unique.to_json(&roots))
+                    .collect();
+
+                let only_here: Vec<Json> = summary.only_here.iter()
+                    .map(|img| Json::Object(img.to_treemap(&roots)))
+                    .collect();
+
+                json_insert!(json, "root", summary.root.display().to_string());
+                json_insert!(json, "shared", shared);
+                json_insert!(json, "only_here", only_here);
+
+                Json::Object(json)
+            })
+            .collect();
+
+        Json::Array(roots_json)
+    }
+
+    pub fn rejections_json(&self, roots: &Roots) -> Json {
+        let rejections_json: Vec<Json> = self.rejections.iter()
+            .map( |rejection| rejection.to_json(roots) )
+            .collect();
+
+        Json::Array(rejections_json)
+    }
+
+    pub fn errors_json(&self, roots: &Roots) -> Json {
         let errors_json: Vec<Json> = self.errors.iter()
-            .map( |error| error.to_json(relative_to) )
+            .map( |error| error.to_json(roots) )
             .collect();
 
         Json::Array(errors_json)        
     }
 
-    pub fn write_info(&self, out: &mut Writer) -> IoResult<()> {
+    pub fn warnings_json(&self, roots: &Roots) -> Json {
+        let warnings_json: Vec<Json> = self.warnings.iter()
+            .map( |warning| warning.to_json(roots) )
+            .collect();
+
+        Json::Array(warnings_json)
+    }
+
+    pub fn linked_json(&self, roots: &Roots) -> Json {
+        let linked_json: Vec<Json> = self.linked.iter()
+            .map( |linked| linked.to_json(roots) )
+            .collect();
+
+        Json::Array(linked_json)
+    }
+
+    pub fn write_info(&self, out: &mut Writer, reference_mode: bool) -> IoResult<()> {
         try!(writeln!(out, "Start time: {}", self.start_time()));
         try!(writeln!(out, "End time: {}", self.end_time()));
         try!(writeln!(out, "Images found: {}", self.total));
         try!(writeln!(out, "Processed: {}", self.uniques.len()));
-        writeln!(out, "Errors: {}", self.errors.len())
+        try!(writeln!(out, "Errors: {}", self.errors.len()));
+        try!(writeln!(out, "Warnings: {}", self.warnings.len()));
+        try!(writeln!(out, "Linked: {}", self.linked.len()));
+        writeln!(out, "Reclaimable: {} bytes", reclaimable(&*self.uniques, reference_mode))
     }
 
-    pub fn write_uniques(&self, out: &mut Writer, relative_to: &Path, dup_only: bool) -> IoResult<()> {
+    pub fn write_uniques(&self, out: &mut Writer, roots: &Roots, dup_only: bool, 
+                         reference_mode: bool) -> IoResult<()> {
+        let (img_label, similars_label) = if reference_mode {
+            ("Candidate", "Closest references")
+        } else {
+            ("Original", "Similars")
+        };
+
         for unique in self.uniques.iter() {
             if dup_only && unique.similars.is_empty() {
                 continue;
             } else {
                 try!(
                     newline_before_after(out, 
-                        |outa| unique.write_self(outa, relative_to))
+                        |outa| unique.write_labeled(outa, roots, img_label, similars_label))
                 );
             }
         }
@@ -95,11 +171,125 @@ impl Results {
         Ok(())
     }
 
-    pub fn write_errors(&self, out: &mut Writer, relative_to: &Path) -> IoResult<()> {
+    pub fn write_roots(&self, out: &mut Writer, settings: &ProgramSettings) -> IoResult<()> {
+        let roots = settings.roots();
+
+        for summary in self.by_root(settings).iter() {
+            try!(writeln!(out, "\nRoot: {}\n", summary.root.display()));
+            try!(out.write_line("Also in other roots:"));
+
+            for unique in summary.shared.iter() {
+                try!(
+                    newline_before_after(out,
+                        |outa| unique.write_labeled(outa, &roots, "Image", "Matches in other roots"))
+                );
+            }
+
+            if settings.dup_only { continue; }
+
+            try!(out.write_line("\nOnly in this root:\n"));
+
+            for img in summary.only_here.iter() {
+                try!(writeln!(out, "({}x{}) {}", 
+                    img.width, img.height, roots.display(&img.path)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Regroup the results by search root, listing for each image in a root 
+    /// the images it matches in other roots.
+    ///
+    /// Since an image only joins the first group it matches, the groups don't show every 
+    /// match across roots, so each image is compared with every image in the other roots.
+    pub fn by_root(&self, settings: &ProgramSettings) -> Vec<RootSummary> {
+        let ignored = load_ignore_list(settings);
+
+        let mut images: Vec<Image> = Vec::new();
+
+        for unique in self.uniques.iter() {
+            images.push(unique.img.clone());
+            images.extend(unique.similars.iter().map(|similar| similar.img.clone()));
+        }
+
+        // In reference mode, a reference may be in several groups
+        images.sort_by(|left, right| left.path.cmp(&right.path));
+        images.dedup();
+
+        let image_roots: Vec<Path> = images.iter().map(|image| settings.root_of(&image.path)).collect();
+
+        let mut roots: BTreeMap<String, RootSummary> = BTreeMap::new();
+        // Rejections were already recorded while grouping
+        let mut rejections = Vec::new();
+
+        for idx in range(0, images.len()) {
+            let ref root = image_roots[idx];
+            let key = root.display().to_string();
+
+            let mut matches = UniqueImage::from_image(images[idx].clone());
+
+            for other_idx in range(0, images.len()) {
+                if image_roots[other_idx] != *root && is_match(&ignored, &mut rejections, &mut matches, 
+                                                              &mut images[other_idx], settings) {
+                    matches.add_similar(images[other_idx].clone());
+                }
+            }
+
+            // So it isn't decoded again when it's matched against the other way around
+            images[idx].pixel_digest = matches.img.pixel_digest.clone();
+
+            if !roots.contains_key(&key) {
+                roots.insert(key.clone(), RootSummary::new(root.clone()));
+            }
+
+            let summary = roots.get_mut(&key).unwrap();
+
+            if matches.similars.is_empty() {
+                summary.only_here.push(matches.img);
+            } else {
+                summary.shared.push(matches);
+            }
+        }
+
+        roots.into_iter().map(|(_, summary)| summary).collect()
+    }
+
+    pub fn write_rejections(&self, out: &mut Writer, roots: &Roots) -> IoResult<()> {
+        for rejection in self.rejections.iter() {
+            try!(rejection.write_self(out, roots));
+        }
+
+        Ok(())
+    }
+
+    pub fn write_errors(&self, out: &mut Writer, roots: &Roots) -> IoResult<()> {
         for error in self.errors.iter() {
             try!(
                 newline_before_after(out, 
-                    |outa| error.write_self(outa, relative_to))
+                    |outa| error.write_self(outa, roots))
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn write_warnings(&self, out: &mut Writer, roots: &Roots) -> IoResult<()> {
+        for warning in self.warnings.iter() {
+            try!(
+                newline_before_after(out, 
+                    |outa| warning.write_self(outa, roots))
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn write_linked(&self, out: &mut Writer, roots: &Roots) -> IoResult<()> {
+        for linked in self.linked.iter() {
+            try!(
+                newline_before_after(out, 
+                    |outa| linked.write_self(outa, roots))
             );
         }
 
@@ -107,42 +297,63 @@ impl Results {
     }
 } 
 
+/// The images of a single search root in cross-root mode.
+pub struct RootSummary {
+    pub root: Path,
+    /// Images in this root with matches in other roots.
+    pub shared: Vec<UniqueImage>,
+    /// Images in this root with no match in any other root.
+    pub only_here: Vec<Image>,
+}
+
+impl RootSummary {
+    fn new(root: Path) -> RootSummary {
+        RootSummary {
+            root: root,
+            shared: Vec::new(),
+            only_here: Vec::new(),
+        }
+    }
+}
+
 pub enum ProcessingError {
     Decoding(Path, ImageError),
     Misc(Path, String),
+    /// An error from an interrupted scan, read back from its journal with its message.
+    Resumed(Path, String),
 }
 
 unsafe impl Send for ProcessingError {}
 
 impl ProcessingError {
     
-    pub fn relative_path(&self, relative_to: &Path) -> Path {
-        let path = match *self {
+    pub fn path(&self) -> &Path {
+        match *self {
             ProcessingError::Decoding(ref path, _) => path,
             ProcessingError::Misc(ref path, _) => path,
-        };
-
-        path.path_relative_from(relative_to).unwrap_or(path.clone())
+            ProcessingError::Resumed(ref path, _) => path,
+        }
     }
-
+    
     pub fn err_msg(&self) -> String {
         match *self {
             ProcessingError::Decoding(_, ref img_err) => format!("Loading error: {}", img_err),
             ProcessingError::Misc(_, ref misc_err) => format!("Hashing error: {}", misc_err),
+            ProcessingError::Resumed(_, ref msg) => msg.clone(),
         }
     }
 
-    pub fn to_json(&self, relative_to: &Path) -> Json {
+    pub fn to_json(&self, roots: &Roots) -> Json {
         let mut json = BTreeMap::new();
 
-        json_insert!(json, "path", self.relative_path(relative_to).display().to_string());
+        roots.insert_json(&mut json, "path", "root", self.path());
         json_insert!(json, "error", self.err_msg());
 
         Json::Object(json)        
     }
 
-    pub fn write_self(&self, out: &mut Writer, relative_to: &Path) -> IoResult<()> {
-        writeln!(out, "Image: {}\n {}\n", self.relative_path(relative_to).display().to_string(), self.err_msg())
+    pub fn write_self(&self, out: &mut Writer, roots: &Roots) -> IoResult<()> {
+        writeln!(out, "Image: {}\n {}\n", roots.display(self.path()), self.err_msg())
     }
 }
 
@@ -158,43 +369,174 @@ pub type Total = uint;
 
 pub fn process(settings: &ProgramSettings, paths: Vec<Path>) -> Results {
     let start_time = now();
+
+    let (mut journal, resumed, paths) = journal::start(settings, paths)
+        .unwrap_or_else(|err| panic!("{}", err));
    
-    let (total, uniques, errors) = process_multithread(settings, paths);
+    let rx = spawn_threads(settings, paths);
+    let (images, errors) = receive_images(rx, &mut journal);
+
+    finish_results(settings, start_time, images, errors, journal, resumed)
+}
+
+/// Like `process`, but search for the images at the same time, so hashing starts with the
+/// first image found. The results include the warnings and linked files from the search.
+pub fn process_streamed(settings: &ProgramSettings) -> Results {
+    let start_time = now();
+
+    let (mut journal, resumed) = journal::open(settings).unwrap_or_else(|err| panic!("{}", err));
+
+    let never_stop = Arc::new(AtomicBool::new(false));
+    let (paths, discovered_rx) = discovery::stream_images(settings, never_stop);
+    let rx = spawn_streamed(settings, paths, resumed.paths(), Arc::new(SearchProgress::new()));
+    let (images, errors) = receive_images(rx, &mut journal);
+
+    let discovered = match discovered_rx.recv_opt() {
+        Ok(result) => result,
+        Err(_) => Err("The search for images stopped unexpectedly".to_string()),
+    };
+
+    let discovered = match discovered {
+        Ok(discovered) => discovered,
+        Err(err) => {
+            // A failed search leaves the journal, so the images hashed so far aren't lost
+            let _ = journal.keep();
+            ::fail(&*err)
+        },
+    };
+
+    let mut results = finish_results(settings, start_time, images, errors, journal, resumed);
+    results.warnings = discovered.warnings;
+    results.linked = discovered.linked;
+
+    results
+}
+
+fn finish_results(settings: &ProgramSettings, start_time: Tm, mut images: Vec<Image>, 
+                  mut errors: Vec<ProcessingError>, mut journal: Journal, resumed: Resumed) -> Results {
+    merge_resumed(settings, &mut images, &mut errors, resumed);
+    journal.finish().unwrap();
+
+    let results = group_results(settings, start_time, images.clone(), errors);
+    keep_digests(settings, &mut *images, &*results.uniques);
+
+    save_hashes(settings, &*images);
+    update_cache(settings, &*images);
+
+    results
+}
+
+/// Combine the results of this run with those from the journal of an interrupted one.
+pub fn merge_resumed(settings: &ProgramSettings, images: &mut Vec<Image>, 
+                     errors: &mut Vec<ProcessingError>, resumed: Resumed) {
+    if resumed.len() == 0 { return; }
+
+    images.extend(resumed.images.into_iter());
+    errors.extend(resumed.errors.into_iter());
+
+    // Copies that were processed on either side of the interruption weren't compared
+    if settings.exact {
+        exact::fill_digests(&mut **images);
+    }
+}
+
+/// Save the hashes of `images` for `--save-hashes` and `--export-index`.
+pub fn save_hashes(settings: &ProgramSettings, images: &[Image]) {
+    if let Some(ref hashes_path) = settings.save_hashes {
+        index::save_hashes(hashes_path, &settings.hash_settings(), images).unwrap();
+    }
+
+    if let Some(ref export_path) = settings.export_index {
+        index::export(export_path, &settings.hash_settings(), &*index::export_roots(settings), images)
+            .unwrap();
+    }
+}
+
+/// Add the hashes of `images` to the cache, if it's enabled.
+pub fn update_cache(settings: &ProgramSettings, images: &[Image]) {
+    if let Some(ref cache_path) = settings.cache {
+        cache::update(cache_path, &settings.hash_settings(), images).unwrap();
+    }
+}
+
+/// Copy the pixel digests filled in while matching onto `images`, so they're saved along with
+/// the hashes and not computed again next time, and store them on the files with `--xattr`.
+pub fn keep_digests(settings: &ProgramSettings, images: &mut [Image], uniques: &[UniqueImage]) {
+    let mut digests: HashMap<Path, String> = HashMap::new();
+
+    for unique in uniques.iter() {
+        let mut matched = vec![&unique.img];
+        matched.extend(unique.similars.iter().map(|similar| &similar.img));
+
+        for image in matched.into_iter() {
+            if let Some(ref digest) = image.pixel_digest {
+                digests.insert(image.path.clone(), digest.clone());
+            }
+        }
+    }
+
+    let hash_settings = settings.hash_settings();
+
+    for image in images.iter_mut().filter(|image| image.pixel_digest.is_none()) {
+        image.pixel_digest = digests.remove(&image.path);
+
+        if settings.xattr && image.pixel_digest.is_some() {
+            let _ = xattr::write(image, &hash_settings);
+        }
+    }
+}
+
+/// Group images that were hashed in a previous run.
+pub fn recluster(settings: &ProgramSettings, images: Vec<Image>) -> Results {
+    group_results(settings, now(), images, Vec::new())
+}
+
+pub fn group_results(settings: &ProgramSettings, start_time: Tm, 
+                 images: Vec<Image>, mut errors: Vec<ProcessingError>) -> Results {
+    let total = images.len();
+    let groups = match_images(images, settings);
+
+    sort_errors(&mut errors);
 
     Results {
         total: total,
         start_time: start_time,
         end_time: now(),
-        uniques: uniques,
+        uniques: groups.uniques,
+        rejections: groups.rejections,
         errors: errors,
+        warnings: Vec::new(),
+        linked: Vec::new(),
     }    
 }
 
-fn process_multithread(settings: &ProgramSettings, paths: Vec<Path>)
-    -> (Total, Vec<UniqueImage>, Vec<ProcessingError>) {                
-    let rx = spawn_threads(settings, paths);
-
-    receive_images(rx, settings)       
-}
-
 pub fn spawn_threads(settings: &ProgramSettings, paths: Vec<Path>) 
     -> Receiver<TimedImageResult> {
+
+    let jobs = if settings.exact {
+        exact::group_exact(paths)
+    } else {
+        paths.into_iter().map(Job::single).collect()
+    };
     
-    let work = ParQueue::from_vec(paths).into_iter();
+    let work = ParQueue::from_vec(jobs).into_iter();
 
     let (tx, rx) = channel();
 
     let hash_settings = settings.hash_settings();
+    let xattr = settings.xattr;
+    let cache = Arc::new(load_cache(settings));
 
     for _ in range(0, settings.threads) {
         let task_tx = tx.clone();
         let mut task_work = work.clone();
+        let task_cache = cache.clone();
 
         Thread::spawn(move || {            
-            for path in task_work {
-                let img_result = load_and_hash_image(&hash_settings, path);
-                                                
-                if task_tx.send_opt(img_result).is_err() { break; }
+            for job in task_work {
+                for img_result in process_job(&hash_settings, task_cache.as_ref(), xattr, job).into_iter() {
+                    if task_tx.send_opt(img_result).is_err() { return; }
+                }
             }
         }).detach();
     }
@@ -202,8 +544,175 @@ pub fn spawn_threads(settings: &ProgramSettings, paths: Vec<Path>)
     rx
 }
 
+/// How many images a search that's still going on has found for processing.
+pub struct SearchProgress {
+    found: AtomicUint,
+    finished: AtomicBool,
+}
+
+impl SearchProgress {
+    pub fn new() -> SearchProgress {
+        SearchProgress {
+            found: AtomicUint::new(0),
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    /// For images that were all found before processing started.
+    pub fn finished(found: uint) -> SearchProgress {
+        SearchProgress {
+            found: AtomicUint::new(found),
+            finished: AtomicBool::new(true),
+        }
+    }
+
+    pub fn found(&self) -> uint {
+        self.found.load(Relaxed)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Relaxed)
+    }
+}
+
+/// Process images as they arrive from `paths`, skipping those in `skip`, e.g. ones already
+/// done in an interrupted scan. `progress` counts the images queued so far.
+///
+/// Since there's no telling how many images are to come, each worker asks for its next image 
+/// when it's done with the last, so a slow image doesn't hold up those queued behind it.
+pub fn spawn_streamed(settings: &ProgramSettings, paths: Receiver<Path>, skip: HashSet<Path>,
+                      progress: Arc<SearchProgress>) -> Receiver<TimedImageResult> {
+    let (tx, rx) = channel();
+
+    let hash_settings = settings.hash_settings();
+    let xattr = settings.xattr;
+    let cache = Arc::new(load_cache(settings));
+
+    // Queue images as soon as they're found, however busy the workers are
+    let (queue_tx, queue_rx) = channel();
+
+    Thread::spawn(move || {
+        for path in paths.iter().filter(|path| !skip.contains(path)) {
+            progress.found.fetch_add(1, Relaxed);
+            if queue_tx.send_opt(path).is_err() { break; }
+        }
+
+        progress.finished.store(true, Relaxed);
+    }).detach();
+
+    let (ready_tx, ready_rx) = channel();
+    let mut job_txs = Vec::with_capacity(settings.threads);
+
+    for idx in range(0, settings.threads) {
+        let (job_tx, job_rx) = channel();
+        job_txs.push(job_tx);
+
+        let task_tx = tx.clone();
+        let task_ready = ready_tx.clone();
+        let task_cache = cache.clone();
+
+        Thread::spawn(move || {
+            while task_ready.send_opt(idx).is_ok() {
+                let path = match job_rx.recv_opt() {
+                    Ok(path) => path,
+                    Err(_) => return,
+                };
+
+                let job = Job::single(path);
+
+                for img_result in process_job(&hash_settings, task_cache.as_ref(), xattr, job).into_iter() {
+                    if task_tx.send_opt(img_result).is_err() { return; }
+                }
+            }
+        }).detach();
+    }
+
+    // Hand each image to the next worker that's ready; the workers stop when `job_txs` is dropped
+    Thread::spawn(move || {
+        for idx in ready_rx.iter() {
+            match queue_rx.recv_opt() {
+                Ok(path) => { let _ = job_txs[idx].send_opt(path); },
+                Err(_) => return,
+            }
+        }
+    }).detach();
+
+    rx
+}
+
+fn load_cache(settings: &ProgramSettings) -> Option<HashCache> {
+    settings.cache.as_ref().map(|cache_path| 
+        HashCache::load(cache_path)
+            .unwrap_or_else(|err| panic!("Could not read hash cache {}: {}", cache_path.display(), err))
+    )
+}
+
+/// Decode and hash the job's file once, then give every exact copy the same result.
+fn process_job(settings: &HashSettings, cache: Option<&HashCache>, xattr: bool, job: Job) 
+    -> Vec<TimedImageResult> {
+    let Job { path, digest, copies } = job;
+
+    let result = load_and_hash_image(settings, cache, xattr, path)
+        .map(|(mut image, load, hash)| {
+            image.digest = digest;
+            (image, load, hash)
+        });
+
+    let mut results = Vec::with_capacity(copies.len() + 1);
+
+    for copy in copies.into_iter() {
+        results.push(match result {
+            Ok((ref image, _, _)) => {
+                let mut image = image.clone();
+
+                if let Ok(stat) = fs::stat(&copy) {
+                    image.modified = stat.modified;
+                }
+
+                image.path = copy;
+                // The copy took no time to load or hash
+                Ok((image, 0, 0))
+            },
+            Err(ref err) => {
+                let msg = format!("Exact copy of {}, which could not be processed: {}", 
+                                  err.path().display(), err.err_msg());
+                Err(ProcessingError::Misc(copy, msg))
+            },
+        });
+    }
+
+    results.insert(0, result);
+
+    results
+}
+
 type ImageLoadResult = Result<DynamicImage, ImageError>;
 
+/// Decode by content where we recognize the format, so mislabeled files still load.
+fn open_image(path: &Path) -> ImageLoadResult {
+    let mut file = try!(File::open(path).map_err(ImageError::IoError));
+
+    match discovery::sniff_reader(&mut file) {
+        Some(format) => {
+            try!(file.seek(0, SeekSet).map_err(ImageError::IoError));
+            image::load(BufferedReader::new(file), format.image_format())
+        },
+        None => image::open(path),
+    }
+}
+
+
+/// Decode `image` again for its pixel digest. Keeping a copy of every image's pixels around
+/// while hashing is costly, and the digest is only needed once its hash matches another's.
+fn fill_pixel_digest(image: &mut Image) {
+    if image.pixel_digest.is_some() { return; }
+
+    // If the file changed or went away since it was hashed, the match is just perceptual
+    if let Ok(Ok(decoded)) = try_fn(|| open_image(&image.path)) {
+        let (width, height) = decoded.dimensions();
+        image.pixel_digest = Some(exact::pixel_digest(width, height, &*decoded.to_rgba().into_vec()));
+    }
+}
 
 fn try_fn<'a, T>(f: || -> T) -> Result<T, Box<&'a str>> {
     let mut maybe: Option<T> = None;
@@ -216,9 +725,43 @@ fn try_fn<'a, T>(f: || -> T) -> Result<T, Box<&'a str>> {
     }        
 }
 
-fn load_and_hash_image(settings: &HashSettings, path: Path) -> TimedImageResult {
+/// Load and hash the image at `path`, unless its extended attribute (if `xattr` is set) 
+/// or `cache` has a hash for it that is still valid.
+pub fn load_and_hash_image(settings: &HashSettings, cache: Option<&HashCache>, xattr: bool, 
+                           path: Path) -> TimedImageResult {
+    let stat = fs::stat(&path).ok();
+
+    let stored = stat.as_ref().and_then(|stat| 
+        if xattr { xattr::read(&path, stat, settings) } else { None }
+            .or_else(|| cache.and_then(|cache| cache.get(&path, stat, settings)))
+    );
+
+    let decoded = stored.is_none();
+
+    let result = match stored {
+        Some(image) => Ok((image, 0, 0)),
+        None => decode_and_hash_image(settings, path),
+    };
+
+    result.map(|(mut image, load_time, hash_time)| {
+        if let Some(stat) = stat {
+            image.size = stat.size;
+            image.modified = stat.modified;
+        }
+
+        // If the attribute can't be written, e.g. on a read-only filesystem, 
+        // the image is just decoded again next time
+        if xattr && decoded {
+            let _ = xattr::write(&image, settings);
+        }
+
+        (image, load_time, hash_time)
+    })
+}
+
+fn decode_and_hash_image(settings: &HashSettings, path: Path) -> TimedImageResult {
     let start_load = precise_time_ns();    
-    let image = try_fn(|| image::open(&path));
+    let image = try_fn(|| open_image(&path));
     let load_time =  precise_time_ns() - start_load;
  
     match image {
@@ -243,62 +786,184 @@ fn try_hash_image(path: Path, img: &DynamicImage, hash_size: u32, fast: bool) ->
     }      
 }
 
-fn receive_images(rx: Receiver<TimedImageResult>, settings: &ProgramSettings) 
-    -> (Total, Vec<UniqueImage>, Vec<ProcessingError>){
-    let mut unique_images = Vec::new();
+pub fn receive_images(rx: Receiver<TimedImageResult>, journal: &mut Journal) 
+    -> (Vec<Image>, Vec<ProcessingError>) {
+    let mut images = Vec::new();
     let mut errors = Vec::new();
-    let mut total = 0u;
    
     for img_result in rx.iter() {
+        journal.record(&img_result).unwrap();
+
         match img_result {
-            Ok((image, _, _)) => {
-                manage_images(&mut unique_images, image, settings);
-                total += 1;
-            },
+            Ok((image, _, _)) => images.push(image),
             Err(img_err) => errors.push(img_err),
         }                
     }
 
-    (total, unique_images, errors)
+    (images, errors)
 }
 
-pub fn manage_images(images: &mut Vec<UniqueImage>, 
-                 image: Image, settings: &ProgramSettings) { 
-    let parent_idx = images
-        .iter()
-        .enumerate()
-        .find(|&(_, parent)| parent.is_similar(&image, settings.threshold))
-        .map(|(idx, _)| idx);
+/// Group all images at once, in order of their paths.
+///
+/// Since `manage_images` is sensitive to the order images arrive in, and the worker threads
+/// finish in no particular order, this keeps the results the same from run to run.
+pub fn match_images(mut images: Vec<Image>, settings: &ProgramSettings) -> Groups {
+    images.sort_by(|left, right| left.path.cmp(&right.path));
+
+    let ignored = load_ignore_list(settings);
+
+    let mut groups = Groups::new(ignored);
+
+    for image in images.into_iter() {
+        manage_images(&mut groups, image, settings);
+    }
+
+    // In reference mode, the candidate is always the original
+    if !settings.reference_mode() {
+        // An image rejected by one group may still have joined a later one
+        let mut grouped: HashSet<Path> = HashSet::new();
+
+        for unique in groups.uniques.iter() {
+            grouped.extend(unique.similars.iter().map(|similar| similar.img.path.clone()));
+        }
+
+        groups.rejections.retain(|rejection| !grouped.contains(&rejection.img));
+
+        for unique in groups.uniques.iter_mut() {
+            keep::choose_original(unique, &*settings.keep);
+        }
+    }
+
+    groups
+}
+
+fn load_ignore_list(settings: &ProgramSettings) -> IgnoreList {
+    IgnoreList::load(&settings.ignore_list)
+        .unwrap_or_else(|err| panic!("Could not read ignore list {}: {}", 
+                                     settings.ignore_list.display(), err))
+}
+
+/// The bytes freed by deleting every duplicate and keeping one image of each group: the similars,
+/// or in reference mode the candidates that match a reference.
+///
+/// A file found under several paths is only processed once, so it's only counted once.
+pub fn reclaimable(uniques: &[UniqueImage], reference_mode: bool) -> u64 {
+    let mut total = 0;
+
+    for unique in uniques.iter().filter(|unique| !unique.similars.is_empty()) {
+        if reference_mode {
+            total += unique.img.size;
+        } else {
+            total += unique.similars.iter().fold(0, |sum, similar| sum + similar.img.size);
+        }
+    }
+
+    total
+}
+
+pub fn sort_errors(errors: &mut Vec<ProcessingError>) {
+    errors.sort_by(|left, right| left.path().cmp(right.path()));
+}
+
+/// The groups built up by `manage_images` as hashed images arrive.
+pub struct Groups {
+    pub uniques: Vec<UniqueImage>,
+    /// Reference images seen so far. Only used in reference mode.
+    pub references: Vec<Image>,
+    /// Hash matches rejected by the dimension guards.
+    pub rejections: Vec<Rejection>,
+    /// Pairs marked as not duplicates.
+    pub ignored: IgnoreList,
+}
+
+impl Groups {
+    pub fn new(ignored: IgnoreList) -> Groups {
+        Groups {
+            uniques: Vec::new(),
+            references: Vec::new(),
+            rejections: Vec::new(),
+            ignored: ignored,
+        }
+    }
+}
+
+pub fn manage_images(groups: &mut Groups, mut image: Image, settings: &ProgramSettings) {
+    if settings.reference_mode() {
+        return manage_reference_images(groups, image, settings);
+    }
+
+    let mut parent_idx = None;
+
+    for (idx, parent) in groups.uniques.iter_mut().enumerate() {
+        if is_match(&groups.ignored, &mut groups.rejections, parent, &mut image, settings) {
+            parent_idx = Some(idx);
+            break;
+        }
+    }
 
     match parent_idx {
-        Some(index) => images[index].add_similar(image),
-        None => images.push(UniqueImage::from_image(image)),
+        Some(index) => groups.uniques[index].add_similar(image),
+        None => groups.uniques.push(UniqueImage::from_image(image)),
     }
 }
 
-pub fn find_images(settings: &ProgramSettings) -> Vec<Path> {
-    use std::io::fs;
+/// In reference mode, every candidate gets its own group containing the references it matches.
+/// Matching goes both ways so it doesn't matter which arrives first.
+fn manage_reference_images(groups: &mut Groups, mut image: Image, settings: &ProgramSettings) {
+    match settings.role_of(&image.path) {
+        Role::Reference => {
+            for candidate in groups.uniques.iter_mut() {
+                if is_match(&groups.ignored, &mut groups.rejections, candidate, &mut image, settings) {
+                    candidate.add_similar(image.clone());
+                }
+            }
 
-    let exts: Vec<&str> = settings.exts.iter().map(|string| string.as_slice()).collect();
+            groups.references.push(image);
+        },
+        Role::Candidate => {
+            let mut candidate = UniqueImage::from_image(image);
 
-    if settings.recurse {
-        fs::walk_dir(&settings.dir)
-            .unwrap()
-            .filter(|file| check_ext(file, &*exts))
-            .collect()   
-    } else {
-        fs::readdir(&settings.dir)
-            .unwrap()
-            .into_iter()
-            .filter(|file| !file.is_dir() && check_ext(file, &*exts))
-            .collect()
-    } 
+            for reference in groups.references.iter_mut() {
+                if is_match(&groups.ignored, &mut groups.rejections, &mut candidate, reference, settings) {
+                    candidate.add_similar(reference.clone());
+                }
+            }
+
+            groups.uniques.push(candidate);
+        },
+    }
 }
 
-fn check_ext(file: &Path, exts: &[&str]) -> bool {   
-    match file.extension_str() {
-        Some(ext) => exts.iter().any(|&a| a.eq_ignore_ascii_case(ext)),
-        None => false
+/// If `image` belongs with `parent`, recording the match if the dimension guards rejected it.
+///
+/// The pixel digests of both are filled in once their hashes match, for the ignore list 
+/// and the match class.
+fn is_match(ignored: &IgnoreList, rejections: &mut Vec<Rejection>, parent: &mut UniqueImage, 
+            image: &mut Image, settings: &ProgramSettings) -> bool {
+    if !may_match(&parent.img, image, settings) { return false; }
+
+    let similarity = parent.is_similar(image, &settings.threshold, &settings.guards());
+
+    if let Similarity::Dissimilar = similarity { return false; }
+
+    if settings.can_decode() {
+        fill_pixel_digest(&mut parent.img);
+        fill_pixel_digest(image);
+    }
+
+    if ignored.is_ignored(&parent.img, image) { return false; }
+
+    match similarity {
+        Similarity::Similar => true,
+        Similarity::Dissimilar => false,
+        Similarity::Rejected(failure) => {
+            rejections.push(Rejection::new(&parent.img, image, failure));
+            false
+        },
     }
 }
 
+/// Checks that don't depend on the hashes, e.g. whether the two paths are allowed to match at all.
+fn may_match(left: &Image, right: &Image, settings: &ProgramSettings) -> bool {
+    !settings.cross_root || settings.root_of(&left.path) != settings.root_of(&right.path)
+}
@@ -0,0 +1,131 @@
+use config::ProgramSettings;
+use img::{Image, UniqueImage};
+use processing::{mod, ProcessingError};
+
+use img_hash::ImageHash;
+
+use serialize::base64::FromBase64;
+
+use time::{Tm, now};
+
+use std::io::fs::{mod, PathExtensions};
+
+pub struct QueryResults {
+    pub start_time: Tm,
+    pub end_time: Tm,
+    /// The number of images the query was compared against.
+    pub searched: uint,
+    /// The query image, with the closest images found as its similars.
+    pub nearest: UniqueImage,
+    pub errors: Vec<ProcessingError>,
+}
+
+/// Hash the query, which is either the path to an image or a hash string from previous output.
+pub fn load_query(query: &str, settings: &ProgramSettings) -> Result<Image, String> {
+    let path = Path::new(query);
+
+    // The query is only read, so its hash isn't stored in an extended attribute
+    if path.is_file() {
+        return processing::load_and_hash_image(&settings.hash_settings(), None, false, path)
+            .map(|(image, _, _)| image)
+            .map_err(|err| err.err_msg());
+    }
+
+    let hash_bytes = (settings.hash_size * settings.hash_size + 7) / 8;
+
+    match query.from_base64() {
+        Ok(ref bytes) if bytes.len() != hash_bytes as uint => return Err(format!(
+            "Query hash is {} bytes, but hashes of size {} are {} bytes: {}", 
+            bytes.len(), settings.hash_size, hash_bytes, query
+        )),
+        _ => (),
+    }
+
+    ImageHash::from_base64(query)
+        .map(|hash| Image::new(Path::new(format!("[{}]", query)), hash, 0, 0))
+        .map_err(|_| format!("Query is neither an image file nor a valid hash: {}", query))
+}
+
+/// Hash every image in `paths` and find the `settings.top` closest to `query`.
+pub fn query(settings: &ProgramSettings, query: Image, paths: Vec<Path>) -> QueryResults {
+    let start_time = now();
+
+    // The query would otherwise be its own closest match
+    let query_id = file_id(&query.path);
+    let paths = paths.into_iter().filter(|path| !is_query(query_id, path)).collect();
+
+    let rx = processing::spawn_threads(settings, paths);
+
+    let mut errors = Vec::new();
+
+    let images: Vec<Image> = rx.iter().filter_map(|img_result| match img_result {
+        Ok((image, _, _)) => Some(image),
+        Err(img_err) => { errors.push(img_err); None },
+    }).collect();
+
+    processing::save_hashes(settings, &*images);
+
+    let (searched, nearest) = find_nearest(query, images.into_iter(), settings.top);
+
+    processing::sort_errors(&mut errors);
+
+    QueryResults {
+        start_time: start_time,
+        end_time: now(),
+        searched: searched,
+        nearest: nearest,
+        errors: errors,
+    }
+}
+
+/// Find the `settings.top` images closest to `query` among images hashed in a previous run.
+pub fn query_hashes(settings: &ProgramSettings, query: Image, images: Vec<Image>) -> QueryResults {
+    let start_time = now();
+
+    let query_id = file_id(&query.path);
+    let images = images.into_iter().filter(|image| !is_query(query_id, &image.path));
+
+    let (searched, nearest) = find_nearest(query, images, settings.top);
+
+    QueryResults {
+        start_time: start_time,
+        end_time: now(),
+        searched: searched,
+        nearest: nearest,
+        errors: Vec::new(),
+    }
+}
+
+/// The device and inode of the file at `path`, to recognize it by whatever path it's found under.
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    fs::stat(path).ok().map(|stat| (stat.unstable.device, stat.unstable.inode))
+}
+
+fn is_query(query_id: Option<(u64, u64)>, path: &Path) -> bool {
+    query_id.is_some() && file_id(path) == query_id
+}
+
+fn find_nearest<I>(query: Image, mut images: I, top: uint) -> (uint, UniqueImage) 
+where I: Iterator<Image> {
+    let mut nearest = UniqueImage::from_image(query);
+    let mut searched = 0u;
+
+    for image in images {
+        nearest.add_similar(image);
+        searched += 1;
+
+        // Don't keep more than we need
+        if nearest.similars.len() > top * 2 {
+            truncate_nearest(&mut nearest, top);
+        }
+    }
+
+    truncate_nearest(&mut nearest, top);
+
+    (searched, nearest)
+}
+
+fn truncate_nearest(nearest: &mut UniqueImage, top: uint) {
+    nearest.similars.sort();
+    nearest.similars.truncate(top);
+}
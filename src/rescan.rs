@@ -0,0 +1,108 @@
+use config::ProgramSettings;
+use exact;
+use img::{Image, UniqueImage};
+use index::{mod, HashIndex};
+use journal;
+use processing::{mod, Results};
+
+use time::now;
+
+use std::collections::{HashMap, HashSet};
+use std::io::fs::{mod, PathExtensions};
+
+/// How the collection changed since the index was last updated.
+pub struct Changes {
+    pub unchanged: uint,
+    /// New or modified images, which were hashed again.
+    pub hashed: uint,
+    /// Images in the index that no longer exist.
+    pub removed: uint,
+}
+
+/// Hash only the images in `paths` that are new or modified since `index` was saved, 
+/// match them against the whole collection, and save the updated index to `index_path`.
+///
+/// Only groups containing a new or modified image are kept in the results.
+pub fn rescan(settings: &ProgramSettings, index_path: &Path, index: Option<HashIndex>, 
+              paths: Vec<Path>) -> (Results, Changes) {
+    let start_time = now();
+
+    let mut indexed: HashMap<Path, Image> = index.map_or(HashMap::new(), |index| 
+        index.images.into_iter().map(|image| (image.path.clone(), image)).collect()
+    );
+
+    let mut images = Vec::new();
+    let mut to_hash = Vec::new();
+
+    for path in paths.into_iter() {
+        match indexed.remove(&path) {
+            Some(ref image) if is_unchanged(image) => images.push(image.clone()),
+            _ => to_hash.push(path),
+        }
+    }
+
+    let (mut journal, resumed, to_hash) = journal::start(settings, to_hash)
+        .unwrap_or_else(|err| panic!("{}", err));
+
+    let rx = processing::spawn_threads(settings, to_hash);
+    let (mut hashed, mut errors) = processing::receive_images(rx, &mut journal);
+
+    processing::merge_resumed(settings, &mut hashed, &mut errors, resumed);
+    journal.finish().unwrap();
+
+    // Images that weren't found this time may only have been left out of this scan, e.g. by 
+    // `--limit`, the filters or a missing `--recurse`, so only those that are gone are removed
+    let (kept, removed): (Vec<Image>, Vec<Image>) = indexed.into_iter()
+        .map(|(_, image)| image)
+        .partition(|image| image.path.exists());
+
+    let changes = Changes {
+        unchanged: images.len(),
+        hashed: hashed.len(),
+        removed: removed.len(),
+    };
+
+    let changed: HashSet<Path> = hashed.iter().map(|image| image.path.clone()).collect();
+
+    images.extend(hashed.into_iter());
+
+    // Copies of old images among the new ones weren't compared while hashing
+    if settings.exact {
+        exact::fill_digests(&mut *images);
+    }
+
+    let mut results = processing::group_results(settings, start_time, images.clone(), errors);
+    processing::keep_digests(settings, &mut *images, &*results.uniques);
+
+    let hashed: Vec<Image> = images.iter()
+        .filter(|image| changed.contains(&image.path))
+        .map(|image| image.clone())
+        .collect();
+
+    processing::update_cache(settings, &*hashed);
+    processing::save_hashes(settings, &*images);
+
+    // Images left out of this scan are kept in the index, but not matched
+    let mut saved = images.clone();
+    saved.extend(kept.into_iter());
+    saved.sort_by(|left, right| left.path.cmp(&right.path));
+
+    index::save_hashes(index_path, &settings.hash_settings(), &*saved).unwrap();
+
+    results.uniques.retain(|unique| involves_any(unique, &changed));
+    results.rejections.retain(|rejection| 
+        changed.contains(&rejection.original) || changed.contains(&rejection.img)
+    );
+
+    (results, changes)
+}
+
+fn is_unchanged(image: &Image) -> bool {
+    fs::stat(&image.path).ok()
+        .map_or(false, |stat| stat.size == image.size && stat.modified == image.modified)
+}
+
+fn involves_any(unique: &UniqueImage, paths: &HashSet<Path>) -> bool {
+    paths.contains(&unique.img.path) 
+        || unique.similars.iter().any(|similar| paths.contains(&similar.img.path))
+}
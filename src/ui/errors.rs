@@ -1,5 +1,7 @@
 use ui::prelude::*;
 
+use config::Roots;
+use discovery::Warning;
 use processing::ProcessingError;
 
 use std::sync::Arc;
@@ -159,15 +161,28 @@ pub struct ErrorBuf {
 }
 
 impl ErrorBuf {
-    fn new(error: ProcessingError, relative_to: &Path) -> ErrorBuf {
+    fn new(error: ProcessingError, roots: &Roots) -> ErrorBuf {
         ErrorBuf {
-            path: error.relative_path(relative_to).display().to_string(),
+            path: roots.display(error.path()),
             message_lines: lines(&*error.err_msg(), 80),
         }             
     }
     
-    pub fn arc_vec(errors: Vec<ProcessingError>, relative_to: &Path) -> Arc<Vec<ErrorBuf>> {
-        Arc::new(errors.into_iter().map(|error| ErrorBuf::new(error, relative_to)).collect())    
+    fn from_warning(warning: &Warning, roots: &Roots) -> ErrorBuf {
+        ErrorBuf {
+            path: roots.display(warning.path()),
+            message_lines: lines(&*format!("Warning: {}", warning.message()), 80),
+        }
+    }
+    
+    /// The errors, followed by the warnings from the search for images.
+    pub fn arc_vec(errors: Vec<ProcessingError>, warnings: &[Warning], roots: &Roots) 
+            -> Arc<Vec<ErrorBuf>> {
+        Arc::new(
+            errors.into_iter().map(|error| ErrorBuf::new(error, roots))
+                .chain(warnings.iter().map(|warning| ErrorBuf::from_warning(warning, roots)))
+                .collect()
+        )    
     }  
 }
 
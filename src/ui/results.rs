@@ -14,6 +14,8 @@ use ui::graphics::{
 use ui::opengl_graphics::Texture;
 use ui::sdl2::mouse::{Cursor, SystemCursor};
 
+use ignore_list;
+use links;
 use img::UniqueImage;
 
 use image::{
@@ -32,8 +34,10 @@ pub fn show_results(results: Results) -> bool {
         avg_hash: results.avg_hash,
         elapsed: results.elapsed,
         total: format!("Total Images Processed: {}", results.total),
-        view_errors: format!("View Errors ({})", results.errors.len()),
-        errors: ErrorBuf::arc_vec(results.errors, &results.search_path),
+        reclaimable: format!("Reclaimable: {} ({} linked)", FormatBytes(results.reclaimable), 
+                             results.linked.len()),
+        view_errors: format!("View Errors ({})", results.errors.len() + results.warnings.len()),
+        errors: ErrorBuf::arc_vec(results.errors, &*results.warnings, &results.roots),
     };
 
 	const WINDOW_SIZE: [u32; 2] = [1024, 768];
@@ -44,7 +48,7 @@ pub fn show_results(results: Results) -> bool {
     let mut done = results.done;
     done.retain(|unique| !unique.similars.is_empty());
 
-	let mut state = match ResultsState::new(done) {
+	let mut state = match ResultsState::new(done, results.ignore_list, results.links_file) {
 		Some(state) => state,
 		None => return scan_again(),
     };
@@ -99,6 +103,7 @@ struct Constants {
     avg_hash: String,
     elapsed: String,
     total: String,
+    reclaimable: String,
     view_errors: String,
     errors: Arc<Vec<ErrorBuf>>,
 }
@@ -109,6 +114,8 @@ struct ResultsState {
     next: Option<UniqueImage>,
     loader: ImgLoader,
     compare_select: Option<uint>,
+    ignore_list: Path,
+    links_file: Path,
     exit: bool,
     buf: Buffers,
     next_str: String,
@@ -117,7 +124,7 @@ struct ResultsState {
 }
 
 impl ResultsState {
-    fn new(mut done: Vec<UniqueImage>) -> Option<ResultsState> {
+    fn new(mut done: Vec<UniqueImage>, ignore_list: Path, links_file: Path) -> Option<ResultsState> {
         match done.pop() {
             Some(current) => {
                 let next = done.pop();
@@ -145,6 +152,8 @@ impl ResultsState {
                         next: next,
                         loader: loader,
                         compare_select: None,
+                        ignore_list: ignore_list,
+                        links_file: links_file,
                         exit: false,
 						buf: buf,
                         next_str: next_str,						
@@ -194,13 +203,23 @@ impl ResultsState {
 			let ref path = self.current.similars[idx].img.path;
 
             print_err(
-                fs::unlink(path).and_then(|_| fs::symlink(&self.current.img.path, path))
+                fs::unlink(path)
+                    .and_then(|_| fs::symlink(&self.current.img.path, path))
+                    .and_then(|_| links::append(&self.links_file, path, &self.current.img.path))
             );
 		}
 
 		self.remove_compare(idx);	
 	}
 
+	fn not_duplicate(&mut self, idx: uint) {
+		print_err(
+			ignore_list::append(&self.ignore_list, &self.current.img, &self.current.similars[idx].img)
+		);
+
+		self.remove_compare(idx);
+	}
+
 	fn remove_compare(&mut self, idx: uint) {
 		self.current.similars.remove(idx);
 		self.buf.compares.remove(idx);
@@ -310,6 +329,11 @@ fn draw_results_ui(
         .position(AVGS_X + 300.0, 5.0)
         .size(18)
         .draw(gl);
+
+    uic.label(&*consts.reclaimable)
+        .position(AVGS_X + 300.0, 25.0)
+        .size(18)
+        .draw(gl);
     
     const VIEW_ERRORS: u64 = NEXT + 1;
     uic.button(VIEW_ERRORS)
@@ -402,6 +426,15 @@ fn draw_results_ui(
 				.callback(|| if confirm_delete() { state.delete(idx); })
 				.draw(gl);
 
+			const NOT_DUPLICATE: u64 = DELETE + 1;
+			uic.button(NOT_DUPLICATE)
+				.label("Not a Duplicate")
+				.label_font_size(18)
+				.right_from(SYMLINK, 5.0)
+				.dimensions(140.0, 30.0)
+				.callback(|| state.not_duplicate(idx))
+				.draw(gl);
+
 			if let Some(similar) = state.buf.compares.get(idx) {
 				uic.label(&*similar.name)
 					.position(699.0, IMG_Y - 45.0)
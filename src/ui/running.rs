@@ -1,11 +1,15 @@
 use ui::prelude::*;
 use ui::dialogs;
+use ui::util;
 
-use config::ProgramSettings;
-use img::UniqueImage;
-use processing::{mod, TimedImageResult, ProcessingError, Total};
+use config::{ProgramSettings, Roots};
+use discovery::{mod, Discovered, LinkedFiles, Warning};
+use img::{Rejection, UniqueImage};
+use journal::{mod, Journal, Resumed};
+use processing::{mod, TimedImageResult, ProcessingError, SearchProgress, Total};
 
 use std::default::Default;
+use std::mem;
 
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Relaxed};
@@ -15,10 +19,18 @@ use std::thread::Thread;
 use time::precise_time_ns;
 
 pub struct Results {
-    pub search_path: Path,
+    pub roots: Roots,
+    pub ignore_list: Path,
+    pub links_file: Path,
     pub total: Total,
     pub done: Vec<UniqueImage>,
     pub errors: Vec<ProcessingError>,
+    /// Hash matches rejected by the dimension guards.
+    pub rejections: Vec<Rejection>,
+    pub warnings: Vec<Warning>,
+    pub linked: Vec<LinkedFiles>,
+    /// The bytes freed by deleting every duplicate found.
+    pub reclaimable: u64,
     pub avg_load: String,
     pub avg_hash: String,
     pub elapsed: String,    
@@ -27,32 +39,62 @@ pub struct Results {
 pub fn start_processing(settings: ProgramSettings) -> Option<Results> {	
 	let (mut uic, mut gl, mut events) = create_window("img-dup running", [570, 80]);
 
-    let paths = processing::find_images(&settings);
+    // Start with 1 second on the clock
+    let start = precise_time_ns() - 1_000_000_000;
 
     let stop = Arc::new(AtomicBool::new(false));
 
-    let mut buf: Buffers = Default::default();
-    buf.total = paths.len();
-    buf.slider_max = buf.total as f64;
+    let (journal, resumed, progress, img_rx, discovered_rx) = if settings.stream_discovery() {
+        let (journal, resumed) = journal::open(&settings).unwrap_or_else(|err| panic!("{}", err));
 
-    // Start with 1 second on the clock
-    let start = precise_time_ns() - 1_000_000_000;
-    let img_rx = processing::spawn_threads(&settings, paths);
+        let progress = Arc::new(SearchProgress::new());
+        let (paths, discovered_rx) = discovery::stream_images(&settings, stop.clone());
+        let img_rx = processing::spawn_streamed(&settings, paths, resumed.paths(), progress.clone());
 
-    let search_path = settings.dir.clone();
+        (journal, resumed, progress, img_rx, discovered_rx)
+    } else {
+        let mut discovered = discovery::find_images(&settings)
+            .unwrap_or_else(|err| panic!("{}", err));
+        let paths = mem::replace(&mut discovered.paths, Vec::new());
 
-    let status_rx = receive_images(img_rx, settings, stop.clone());
+        let (journal, resumed, paths) = journal::start(&settings, paths)
+            .unwrap_or_else(|err| panic!("{}", err));
+
+        let progress = Arc::new(SearchProgress::finished(paths.len()));
+        let img_rx = processing::spawn_threads(&settings, paths);
+
+        let (discovered_tx, discovered_rx) = channel();
+        discovered_tx.send(Ok(discovered));
+
+        (journal, resumed, progress, img_rx, discovered_rx)
+    };
+
+    let mut buf: Buffers = Default::default();
+
+    let roots = settings.roots();
+    let ignore_list = settings.ignore_list.clone();
+    let links_file = settings.links_file();
+    let reference_mode = settings.reference_mode();
+
+    let status_rx = receive_images(img_rx, discovered_rx, settings, journal, resumed, stop.clone());
    		
 	for event in events {
+        buf.set_found(progress.found(), !progress.is_finished());
         buf.set_elapsed(precise_time_ns() - start);
 
         if buf.stop { stop.store(true, Relaxed); }
 
         match status_rx.try_recv() {
             Ok(Message::Update(status)) => buf.status_update(status),
-            Ok(Message::Finished(total, done, errors)) => { 
-                return Some(buf.into_results(total, done, errors, search_path)); 
+            Ok(Message::Finished(total, done, rejections, errors, discovered)) => { 
+                let reclaimable = processing::reclaimable(&*done, reference_mode);
+
+                return Some(buf.into_results(
+                    total, done, rejections, errors, discovered, reclaimable, 
+                    roots, ignore_list, links_file
+                )); 
             },
+            Ok(Message::Failed(err)) => panic!("{}", err),
             Err(_) => (),
         }
 
@@ -79,8 +121,13 @@ struct Buffers {
     count: String,
     elapsed: String,
     elapsed_ns: u64,
+    processed: uint,
+    errors: uint,
     done: uint, 
+    /// The images found so far.
     total: uint,
+    /// If the search for images is still going on, so `total` may grow.
+    searching: bool,
     stop: bool,
     slider_cur: f64,
     slider_max: f64,
@@ -93,16 +140,39 @@ impl Buffers {
         write_str!(self.avg_load, "Avg Load (ms): {}", ns_to_ms(status.avg_load));
         write_str!(self.avg_hash, "Avg Hash (ms): {}", ns_to_ms(status.avg_hash));
 
+        self.processed = status.count;
+        self.errors = status.errors;
         self.done = status.count + status.errors;
+        self.slider_cur = self.done as f64;
+
+        self.write_progress();
+    }
+
+    fn set_found(&mut self, found: uint, searching: bool) {
+        if found == self.total && searching == self.searching { return; }
+
+        self.total = found;
+        self.searching = searching;
+        self.slider_max = found as f64;
+
+        self.count.clear();
+        self.percent.clear();
+        self.write_progress();
+        self.update_est_time();
+    }
+
+    fn write_progress(&mut self) {
+        // Until the search is done, there may be more to come
+        let more = if self.searching { "+" } else { "" };
 
         write_str!(self.count, 
-            "Current (Errors) / Total: {} ({}) / {}", 
-            status.count, status.errors, self.total
+            "Current (Errors) / Total: {} ({}) / {}{}", 
+            self.processed, self.errors, self.total, more
         );
 
-        self.slider_cur = status.count as f64 + status.errors as f64;
+        let percent = if self.total > 0 { self.slider_cur / self.slider_max * 100.0 } else { 0.0 };
 
-        write_str!(self.percent, "{:.02}%", (self.slider_cur / self.slider_max) * 100.0);
+        write_str!(self.percent, "{:.02}%", percent);
     }
 
     fn update_est_time(&mut self) {
@@ -115,8 +185,10 @@ impl Buffers {
         };
 
         let (hr, min, sec) = secs_to_hr_min_sec(est_secs);
+        // Only an estimate for the images found so far
+        let more = if self.searching { "+" } else { "" };
 
-        write_str!(self.est_time_rem, "ETA: {}:{:02}:{:02}", hr, min, sec);
+        write_str!(self.est_time_rem, "ETA: {}:{:02}:{:02}{}", hr, min, sec, more);
     }
 
     fn set_elapsed(&mut self, elapsed_ns: u64) {
@@ -148,14 +220,25 @@ impl Buffers {
         self, 
         total: Total, 
         done: Vec<UniqueImage>, 
+        rejections: Vec<Rejection>, 
         errors: Vec<ProcessingError>, 
-        search_path: Path
+        discovered: Discovered,
+        reclaimable: u64,
+        roots: Roots,
+        ignore_list: Path,
+        links_file: Path,
     ) -> Results { 
         Results {
-            search_path: search_path,
+            roots: roots,
+            ignore_list: ignore_list,
+            links_file: links_file,
             total: total,
             done: done,
             errors: errors,
+            rejections: rejections,
+            warnings: discovered.warnings,
+            linked: discovered.linked,
+            reclaimable: reclaimable,
             avg_hash: self.avg_hash,
             avg_load: self.avg_load,
             elapsed: self.elapsed,
@@ -194,7 +277,9 @@ struct Status {
 
 enum Message {
     Update(Status),
-    Finished(Total, Vec<UniqueImage>, Vec<ProcessingError>),
+    Finished(Total, Vec<UniqueImage>, Vec<Rejection>, Vec<ProcessingError>, Discovered),
+    /// The search for images failed.
+    Failed(String),
 }
 
 fn draw_running_dialog(gl: &mut Gl, uic: &mut UiContext, buf: &mut Buffers) {
@@ -246,12 +331,18 @@ fn draw_running_dialog(gl: &mut Gl, uic: &mut UiContext, buf: &mut Buffers) {
         .draw(gl);
 }
 
-fn receive_images(img_rx: Receiver<TimedImageResult>, settings: ProgramSettings, stop: Arc<AtomicBool>) 
--> Receiver<Message> {
+fn receive_images(
+    img_rx: Receiver<TimedImageResult>, 
+    discovered_rx: Receiver<Result<Discovered, String>>,
+    settings: ProgramSettings, 
+    mut journal: Journal,
+    resumed: Resumed,
+    stop: Arc<AtomicBool>
+) -> Receiver<Message> {
     let (status_tx, status_rx) = channel();
     
     Thread::spawn(move |:| {        
-        let mut unique_images = Vec::new();
+        let mut images = Vec::new();
         let mut errors = Vec::new();
 
         let mut total_load = 0u64;
@@ -259,11 +350,14 @@ fn receive_images(img_rx: Receiver<TimedImageResult>, settings: ProgramSettings,
         let mut count = 0u64;
 
         for img_result in img_rx.iter() {
+            // The journal is kept when stopped, so the scan can be resumed later
             if stop.load(Relaxed) { break; }
 
+            util::print_err(journal.record(&img_result));
+
             match img_result {
                 Ok((image, load, hash)) => {
-                    processing::manage_images(&mut unique_images, image, &settings);
+                    images.push(image);
                     count += 1;
                     total_load += load;
                     total_hash += hash;
@@ -278,8 +372,54 @@ fn receive_images(img_rx: Receiver<TimedImageResult>, settings: ProgramSettings,
                 errors: errors.len(),
             })).is_err() { return; };
         }
+
+        let stopped = stop.load(Relaxed);
+
+        // Let the workers and the search wind down instead of waiting for them
+        drop(img_rx);
+
+        let discovered = if stopped {
+            // Only what the search found if it already finished, as it stops at the next file
+            match discovered_rx.try_recv() {
+                Ok(Ok(discovered)) => discovered,
+                _ => Discovered { paths: Vec::new(), warnings: Vec::new(), linked: Vec::new() },
+            }
+        } else {
+            let result = match discovered_rx.recv_opt() {
+                Ok(result) => result,
+                Err(_) => Err("The search for images stopped unexpectedly".to_string()),
+            };
+
+            match result {
+                Ok(discovered) => discovered,
+                Err(err) => { 
+                    // A failed search leaves the journal, so the images hashed so far aren't lost
+                    util::print_err(journal.keep());
+                    let _ = status_tx.send_opt(Message::Failed(err)); 
+                    return; 
+                },
+            }
+        };
       
-        status_tx.send(Message::Finished(count as uint + errors.len(), unique_images, errors));    
+        processing::merge_resumed(&settings, &mut images, &mut errors, resumed);
+
+        // The journal is kept when stopped, so the scan can be resumed later
+        if stopped {
+            util::print_err(journal.keep());
+        } else {
+            util::print_err(journal.finish());
+        }
+
+        // Matching is done all at once so the results don't depend on thread scheduling
+        let groups = processing::match_images(images.clone(), &settings);
+
+        processing::keep_digests(&settings, &mut *images, &*groups.uniques);
+        processing::update_cache(&settings, &*images);
+        processing::sort_errors(&mut errors);
+
+        status_tx.send(Message::Finished(
+            count as uint + errors.len(), groups.uniques, groups.rejections, errors, discovered
+        ));    
     }).detach();
     
     status_rx
@@ -1,13 +1,13 @@
 use ui::prelude::*;
 
-use config::ProgramSettings;
+use config::{ProgramSettings, Threshold};
 
 use std::default::Default;
 
 pub fn show_setup_ui(settings: ProgramSettings) -> Option<ProgramSettings> {	
 	let (mut state, mut buf) = ConfigState::from_settings(settings);
 
-	let (mut uic, mut gl, mut events) = create_window("img-dup configuration", [740, 120]);
+	let (mut uic, mut gl, mut events) = create_window("img-dup configuration", [740, 185]);
 		
 	for event in events {
         if state.canceled { break; }
@@ -38,9 +38,11 @@ impl ConfigState {
 		let mut buffers: Buffers = Default::default();
 
 		write_str!(buffers.threads, "{}", settings.threads);
-		write_str!(buffers.dir, "{}", settings.dir.display());
+		write_str!(buffers.dir, "{}", settings.dir().display());
         write_str!(buffers.hash_size, "{}", settings.hash_size);
-        buffers.set_threshold(settings.threshold); 
+        buffers.set_threshold(settings.threshold.ratio(settings.hash_size)); 
+        write_str!(buffers.include, "{}", settings.include.connect(PATTERN_SEP));
+        write_str!(buffers.exclude, "{}", settings.exclude.connect(PATTERN_SEP));
 
 		(
             ConfigState {
@@ -61,14 +63,15 @@ impl ConfigState {
 		}
 	}
 
+	// Only the first directory is shown; any others from the command line are kept
 	fn update_dir(&mut self, dir: &mut String) {	
-		self.settings.dir = Path::new(&**dir);
+		self.settings.dirs[0] = Path::new(&**dir);
 	}
 
     fn set_dir(&mut self, buf: &mut Buffers, dir: Path) {
         buf.dir.clear();
         write_str!(buf.dir, "{}", dir.display());
-        self.settings.dir = dir;
+        self.settings.dirs[0] = dir;
     }
 
     fn add_threads(&mut self, buf: &mut Buffers, threads: int) {
@@ -101,10 +104,30 @@ impl ConfigState {
     }
 
     fn set_threshold(&mut self, buf: &mut Buffers, threshold: f32) {
-        self.settings.threshold = threshold;
+        self.settings.threshold = Threshold::Ratio(threshold);
 
         buf.set_threshold(threshold);
     }
+
+    fn update_include(&mut self, include: &mut String) {
+        self.settings.include = split_patterns(&**include);
+    }
+
+    fn update_exclude(&mut self, exclude: &mut String) {
+        self.settings.exclude = split_patterns(&**exclude);
+    }
+}
+
+/// Separates glob patterns in the include and exclude boxes.
+/// Not a comma, since those are common in file names.
+const PATTERN_SEP: &'static str = "; ";
+
+fn split_patterns(patterns: &str) -> Vec<String> {
+    patterns.split(';')
+        .map(|pattern| pattern.trim())
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| pattern.to_string())
+        .collect()
 }
 
 #[deriving(Default)]
@@ -113,6 +136,8 @@ struct Buffers {
     hash_size: String,
     threshold: String,
 	dir: String,
+    include: String,
+    exclude: String,
 }
 
 impl Buffers {
@@ -146,7 +171,7 @@ fn draw_setup_dialog(gl: &mut Gl, uic: &mut UiContext, state: &mut ConfigState,
         .label_font_size(18)
         .right_from(DIR, 5.0)
         .callback(|| 
-            if let Some(dir) = open_folder_dialog(&state.settings.dir) {
+            if let Some(dir) = open_folder_dialog(state.settings.dir()) {
                 state.set_dir(buf, dir)
             }
         )
@@ -207,7 +232,8 @@ fn draw_setup_dialog(gl: &mut Gl, uic: &mut UiContext, state: &mut ConfigState,
         .draw(gl);
         
     const THRESHOLD: u64 = USE_DCT + 1;
-    uic.slider(THRESHOLD, state.settings.threshold, 0.01, 0.10)
+    let threshold = state.settings.threshold.ratio(state.settings.hash_size);
+    uic.slider(THRESHOLD, threshold, 0.01, 0.10)
         .right_from(USE_DCT, 50.0)
         .dimensions(240.0, 30.0)
         .callback(|threshold| state.set_threshold(buf, threshold))
@@ -218,7 +244,33 @@ fn draw_setup_dialog(gl: &mut Gl, uic: &mut UiContext, state: &mut ConfigState,
         .size(18)
         .draw(gl);
     
-    const GO: u64 = THRESHOLD + 1;
+    const INCLUDE: u64 = THRESHOLD + 1;
+    uic.text_box(INCLUDE, &mut buf.include)
+        .font_size(18)
+        .down_from(THREADS, 30.0)
+        .dimensions(330.0, 30.0)
+        .callback(|include| state.update_include(include))
+        .draw(gl);
+
+    uic.label("Include (separated by ;)")
+        .size(18)
+        .up_from(INCLUDE, 20.0)
+        .draw(gl);
+
+    const EXCLUDE: u64 = INCLUDE + 1;
+    uic.text_box(EXCLUDE, &mut buf.exclude)
+        .font_size(18)
+        .right_from(INCLUDE, 5.0)
+        .dimensions(330.0, 30.0)
+        .callback(|exclude| state.update_exclude(exclude))
+        .draw(gl);
+
+    uic.label("Exclude (separated by ;)")
+        .size(18)
+        .up_from(EXCLUDE, 20.0)
+        .draw(gl);
+
+    const GO: u64 = EXCLUDE + 1;
     uic.button(GO)
         .label("Go!")
         .down_from(BROWSE, 30.0)
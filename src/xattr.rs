@@ -0,0 +1,134 @@
+use config::HashSettings;
+use img::Image;
+
+use img_hash::ImageHash;
+
+use std::io::{FileStat, IoResult};
+
+/// The extended attribute the hash is stored in.
+const ATTR_NAME: &'static str = "user.img_dup.hash";
+const VERSION: &'static str = "img_dup1";
+
+/// The hash stored on the file at `path`, if it was made with `settings` and the file 
+/// hasn't changed since.
+///
+/// Unlike the hash cache, the attribute follows the file when it's moved or renamed 
+/// within the same filesystem.
+pub fn read(path: &Path, stat: &FileStat, settings: &HashSettings) -> Option<Image> {
+    get_attr(path).and_then(|value| parse_value(&*value, path, stat, settings))
+}
+
+/// Store the hash of `image` on the file itself.
+pub fn write(image: &Image, settings: &HashSettings) -> IoResult<()> {
+    let value = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        VERSION, settings.hash_size, settings.fast,
+        image.size, image.modified, image.width, image.height,
+        image.hash.to_base64(),
+        image.pixel_digest.as_ref().map_or("-", |digest| &**digest)
+    );
+
+    set_attr(&image.path, &*value)
+}
+
+fn parse_value(value: &str, path: &Path, stat: &FileStat, settings: &HashSettings) -> Option<Image> {
+    let fields: Vec<&str> = value.split('\t').collect();
+
+    if fields.len() != 9 || fields[0] != VERSION { return None; }
+
+    let hash = ImageHash::from_base64(fields[7]).ok();
+    let pixel_digest = if fields[8] == "-" { None } else { Some(fields[8].to_string()) };
+
+    match (fields[1].parse(), fields[2].parse(), fields[3].parse(), fields[4].parse(),
+           fields[5].parse(), fields[6].parse(), hash) {
+        (Some(hash_size), Some(fast), Some(size), Some(modified), 
+         Some(width), Some(height), Some(hash)) => {
+            // Writing the file doesn't touch the attribute, so make sure it's still current
+            if hash_size != settings.hash_size || fast != settings.fast 
+                || size != stat.size || modified != stat.modified {
+                return None;
+            }
+
+            let mut image = Image::new(path.clone(), hash, width, height);
+            image.pixel_digest = pixel_digest;
+            Some(image)
+        },
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod ffi {
+    use libc::{c_char, c_int, c_void, size_t, ssize_t};
+
+    extern {
+        pub fn getxattr(path: *const c_char, name: *const c_char, 
+                        value: *mut c_void, size: size_t) -> ssize_t;
+        pub fn setxattr(path: *const c_char, name: *const c_char, 
+                        value: *const c_void, size: size_t, flags: c_int) -> c_int;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn get_attr(path: &Path) -> Option<String> {
+    use libc::{c_int, c_void, size_t, ERANGE};
+    use std::c_str::ToCStr;
+    use std::{os, ptr};
+
+    let c_path = path.to_c_str();
+    let c_name = ATTR_NAME.to_c_str();
+
+    loop {
+        // The hash grows with the hash size, so ask how long the value is first
+        let size = unsafe {
+            ffi::getxattr(c_path.as_ptr(), c_name.as_ptr(), ptr::null_mut(), 0)
+        };
+
+        // Most often the attribute just isn't there, which isn't an error for us
+        if size < 0 { return None; }
+
+        let mut buf: Vec<u8> = Vec::from_elem(size as uint, 0u8);
+
+        let len = unsafe {
+            ffi::getxattr(c_path.as_ptr(), c_name.as_ptr(), 
+                          buf.as_mut_ptr() as *mut c_void, buf.len() as size_t)
+        };
+
+        if len < 0 {
+            // The value grew since we asked, e.g. another run stored a larger hash
+            if os::errno() as c_int == ERANGE { continue; }
+
+            return None;
+        }
+
+        buf.truncate(len as uint);
+
+        return String::from_utf8(buf).ok();
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_attr(path: &Path, value: &str) -> IoResult<()> {
+    use libc::{c_void, size_t};
+    use std::c_str::ToCStr;
+    use std::io::IoError;
+
+    let c_path = path.to_c_str();
+    let c_name = ATTR_NAME.to_c_str();
+
+    let ret = unsafe {
+        ffi::setxattr(c_path.as_ptr(), c_name.as_ptr(), 
+                      value.as_ptr() as *const c_void, value.len() as size_t, 0)
+    };
+
+    if ret < 0 { Err(IoError::last_error()) } else { Ok(()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_attr(_: &Path) -> Option<String> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_attr(_: &Path, _: &str) -> IoResult<()> {
+    Ok(())
+}